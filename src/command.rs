@@ -0,0 +1,378 @@
+use config::Enforcement;
+use events;
+use server::{Command, ErrorCode};
+
+/// Why a command was refused, as sent back to the client
+pub type CmdError = (ErrorCode, &'static str);
+
+/// A mixer channel
+pub struct PortRef {
+    pub is_output: bool,
+    pub name: String,
+}
+
+/// New value of an on/off property
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Switch {
+    On,
+    Off,
+    Toggle,
+}
+
+impl Switch {
+    /// Toggle when no value is given
+    pub fn parse(val: Option<&str>) -> Result<Self, CmdError> {
+        match val {
+            Some("on") | Some("true") | Some("1") => Ok(Switch::On),
+            Some("off") | Some("false") | Some("0") => Ok(Switch::Off),
+            Some("tog") | Some("toggle") | None => Ok(Switch::Toggle),
+            Some(_) => Err((ErrorCode::BadArgs, "Bad value!")),
+        }
+    }
+
+    pub fn apply(self, cur: bool) -> bool {
+        match self {
+            Switch::On => true,
+            Switch::Off => false,
+            Switch::Toggle => !cur,
+        }
+    }
+}
+
+/// Option `i` of a command
+fn arg(opts: &[String], i: usize) -> Result<&str, CmdError> {
+    opts.get(i)
+        .map(|o| o.as_str())
+        .ok_or((ErrorCode::BadArgs, "Missing argument!"))
+}
+
+/// Whether a port type option names outputs
+fn ptype(pt: &str) -> Result<bool, CmdError> {
+    match pt {
+        "input" | "in" | "i" => Ok(false),
+        "output" | "out" | "o" => Ok(true),
+        _ => Err((ErrorCode::BadArgs, "Bad port type!")),
+    }
+}
+
+/// Balance option, clamped to -1 (right) to 1 (left)
+fn balance(val: &str) -> Result<f32, CmdError> {
+    match val.parse::<f32>() {
+        Ok(bal) if bal.is_finite() => Ok(bal.max(-1.0).min(1.0)),
+        _ => Err((ErrorCode::BadArgs, "Bad value!")),
+    }
+}
+
+/// Port given by options `i` (type) and `i + 1` (name)
+fn port(opts: &[String], i: usize) -> Result<PortRef, CmdError> {
+    Ok(PortRef {
+        is_output: ptype(arg(opts, i)?)?,
+        name: arg(opts, i + 1)?.to_owned(),
+    })
+}
+
+/// `con`, `dis` and `tog`
+fn switch_cmd(cmd: &str) -> Switch {
+    match cmd {
+        "con" => Switch::On,
+        "dis" => Switch::Off,
+        _ => Switch::Toggle,
+    }
+}
+
+/// Commands handled by the mixer (targets `myx`, `cfg` and `all`)
+pub enum MixerCmd {
+    /// (Dis)connect an input (first) to an output (second)
+    Connect(Switch, String, String),
+    GetChannels,
+    GetMonitor,
+    /// Send from an input (first) to an output (second)
+    GetSend(String, String),
+    GetPort(PortRef),
+    /// One-shot subscription
+    Monitor(events::Filter),
+    Subscribe(events::Filter),
+    Unsubscribe(usize),
+    /// Volume as given by the client, parsed against the current fader
+    /// position when applied
+    SetVolume(PortRef, String),
+    SetBalance(PortRef, f32),
+    /// Send level from an input (first) to an output (second), parsed like
+    /// volumes
+    SetSend(String, String, String),
+    SetSendMute(String, String, Switch),
+    SetMute(PortRef, Switch),
+    SetSolo(PortRef, Switch),
+    SetMonitor(PortRef),
+    Meters,
+    /// Stream the meters at the given rate (or the default one)
+    StreamMeters(Option<f32>),
+    /// Create a channel, mono if set
+    CreatePort(PortRef, bool),
+    RemovePort(PortRef),
+    RenamePort(PortRef, String),
+    Save(Option<String>),
+    Load(Option<String>),
+}
+
+impl MixerCmd {
+    pub fn parse(c: &Command) -> Result<Self, CmdError> {
+        let opts = &c.opts[..];
+        let bad = Err((ErrorCode::BadCommand, "Bad command!"));
+
+        match c.target.as_str() {
+            "cfg" | "all" => {
+                return match c.cmd.as_str() {
+                    "save" => Ok(MixerCmd::Save(opts.get(0).cloned())),
+                    "load" => Ok(MixerCmd::Load(opts.get(0).cloned())),
+                    _ => bad,
+                };
+            }
+            _ => {}
+        }
+
+        Ok(match c.cmd.as_str() {
+            "con" | "dis" | "tog" => MixerCmd::Connect(
+                switch_cmd(&c.cmd),
+                arg(opts, 0)?.to_owned(),
+                arg(opts, 1)?.to_owned(),
+            ),
+            "get" => match arg(opts, 0)? {
+                "ports" | "channels" => MixerCmd::GetChannels,
+                "monitor" | "mon" => MixerCmd::GetMonitor,
+                "send" => MixerCmd::GetSend(arg(opts, 1)?.to_owned(), arg(opts, 2)?.to_owned()),
+                _ => MixerCmd::GetPort(port(opts, 0)?),
+            },
+            "mon" => {
+                let filter = match arg(opts, 0)? {
+                    "ports" | "channels" => events::Filter::from_opts(&opts[..1]),
+                    _ if opts.len() < 3 => return Err((ErrorCode::BadArgs, "Missing argument!")),
+                    _ => events::Filter::from_opts(opts),
+                };
+                MixerCmd::Monitor(filter.map_err(|_| (ErrorCode::BadArgs, "Bad filter!"))?)
+            }
+            "subscribe" | "sub" => MixerCmd::Subscribe(
+                events::Filter::from_opts(opts).map_err(|_| (ErrorCode::BadArgs, "Bad filter!"))?,
+            ),
+            "unsubscribe" | "unsub" => MixerCmd::Unsubscribe(
+                arg(opts, 0)?
+                    .parse()
+                    .map_err(|_| (ErrorCode::BadArgs, "Bad value!"))?,
+            ),
+            "set" => match arg(opts, 0)? {
+                "volume" | "volule" | "vol" | "v" => {
+                    MixerCmd::SetVolume(port(opts, 1)?, arg(opts, 3)?.to_owned())
+                }
+                "balance" | "bal" | "b" => MixerCmd::SetBalance(port(opts, 1)?, balance(arg(opts, 3)?)?),
+                "send" => MixerCmd::SetSend(
+                    arg(opts, 1)?.to_owned(),
+                    arg(opts, 2)?.to_owned(),
+                    arg(opts, 3)?.to_owned(),
+                ),
+                "send_mute" => MixerCmd::SetSendMute(
+                    arg(opts, 1)?.to_owned(),
+                    arg(opts, 2)?.to_owned(),
+                    Switch::parse(opts.get(3).map(|o| o.as_str()))?,
+                ),
+                "mute" => MixerCmd::SetMute(port(opts, 1)?, Switch::parse(opts.get(3).map(|o| o.as_str()))?),
+                "solo" => MixerCmd::SetSolo(port(opts, 1)?, Switch::parse(opts.get(3).map(|o| o.as_str()))?),
+                "monitor" | "mon" | "m" => MixerCmd::SetMonitor(port(opts, 1)?),
+                _ => return Err((ErrorCode::BadArgs, "Bad property!")),
+            },
+            "meters" | "mtr" => match opts.get(0).map(|o| o.as_str()) {
+                None | Some("get") => MixerCmd::Meters,
+                Some("stream") => MixerCmd::StreamMeters(match opts.get(1) {
+                    Some(r) => Some(r.parse().map_err(|_| (ErrorCode::BadArgs, "Bad rate!"))?),
+                    None => None,
+                }),
+                Some(_) => return Err((ErrorCode::BadArgs, "Bad meters command!")),
+            },
+            "mkp" => MixerCmd::CreatePort(
+                port(opts, 0)?,
+                match opts.get(2).map(|m| m.as_str()) {
+                    Some("mono") | Some("m") => true,
+                    Some("stereo") | Some("s") | None => false,
+                    Some(_) => return Err((ErrorCode::BadArgs, "Bad mode!")),
+                },
+            ),
+            "rmp" => MixerCmd::RemovePort(port(opts, 0)?),
+            "mvp" => MixerCmd::RenamePort(port(opts, 0)?, arg(opts, 2)?.to_owned()),
+            _ => return bad,
+        })
+    }
+
+    /// Whether the mixer snapshot has to be rebuilt after the command
+    pub fn changes_mix(&self) -> bool {
+        match *self {
+            MixerCmd::Connect(..)
+            | MixerCmd::SetVolume(..)
+            | MixerCmd::SetBalance(..)
+            | MixerCmd::SetSend(..)
+            | MixerCmd::SetSendMute(..)
+            | MixerCmd::SetMute(..)
+            | MixerCmd::SetSolo(..)
+            | MixerCmd::SetMonitor(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Filter for the connection events of every port, or of a single one
+fn patchbay_filter(name: Option<&String>) -> events::Filter {
+    events::Filter {
+        property: Some("patchbay".to_owned()),
+        is_output: None,
+        name: name.cloned(),
+    }
+}
+
+/// Enforcement policy option, `none` dropping the one set for a port
+fn enforcement(policy: &str) -> Result<Option<Enforcement>, CmdError> {
+    match policy {
+        "strict" => Ok(Some(Enforcement::Strict)),
+        "additive" => Ok(Some(Enforcement::Additive)),
+        "ignore" => Ok(Some(Enforcement::Ignore)),
+        "none" => Ok(None),
+        _ => Err((ErrorCode::BadArgs, "Bad policy!")),
+    }
+}
+
+/// What to do with a snapshot of the JACK graph
+pub enum CaptureTo {
+    /// Only send it back
+    Show,
+    /// Add it to the rules
+    Merge,
+    /// Write it to a profile file
+    Save(String),
+}
+
+/// Commands handled by the patchbay (target `con`)
+pub enum PatchbayCmd {
+    /// (Dis)connect a JACK output port (first) to an input port (second)
+    Connect(Switch, String, String),
+    /// Add or remove a rule that never lets a JACK output port (first) be
+    /// connected to an input port (second)
+    Forbid(Switch, String, String),
+    /// Every rule, or only the ones that aren't in place if set
+    GetRules(bool),
+    GetPort(String),
+    GetGraph,
+    /// (Dis)connections JACK refused that are being retried or were given up
+    GetFailures,
+    /// Fallback groups, with the route each one uses
+    GetFallbacks,
+    /// Every enforcement policy, or the one that applies to a port or client
+    GetPolicy(Option<String>),
+    /// Set the enforcement policy of the ports or clients matching a name or
+    /// a pattern (`None` drops it), or the default one
+    SetPolicy(Option<Enforcement>, Option<String>),
+    /// Every JACK port, or the ones whose name matches a JACK (POSIX
+    /// extended) regex
+    ListPorts(Option<String>),
+    /// Same as `ListPorts`, grouped by client
+    ListClients(Option<String>),
+    /// Snapshot the JACK connections as rules, only those of the given clients
+    /// (names or patterns) if any, with PIDs in client names turned into
+    /// wildcards if set
+    Capture(CaptureTo, Vec<String>, bool),
+    /// Add the rules of a profile file to the rules in use
+    Merge(String),
+    /// One-shot subscription
+    Monitor(events::Filter),
+    Subscribe(events::Filter),
+    Unsubscribe(usize),
+}
+
+impl PatchbayCmd {
+    pub fn parse(c: &Command) -> Result<Self, CmdError> {
+        let opts = &c.opts[..];
+        Ok(match c.cmd.as_str() {
+            "con" | "dis" | "tog" => PatchbayCmd::Connect(
+                switch_cmd(&c.cmd),
+                arg(opts, 0)?.to_owned(),
+                arg(opts, 1)?.to_owned(),
+            ),
+            "forbid" | "allow" => PatchbayCmd::Forbid(
+                if c.cmd == "forbid" { Switch::On } else { Switch::Off },
+                arg(opts, 0)?.to_owned(),
+                arg(opts, 1)?.to_owned(),
+            ),
+            "get" => match arg(opts, 0)? {
+                "rules" => match opts.get(1).map(|o| o.as_str()) {
+                    None | Some("all") => PatchbayCmd::GetRules(false),
+                    Some("failing") => PatchbayCmd::GetRules(true),
+                    Some(_) => return Err((ErrorCode::BadArgs, "Bad value!")),
+                },
+                "port" => PatchbayCmd::GetPort(arg(opts, 1)?.to_owned()),
+                "graph" => PatchbayCmd::GetGraph,
+                "failures" | "failed" => PatchbayCmd::GetFailures,
+                "fallbacks" => PatchbayCmd::GetFallbacks,
+                "policy" => PatchbayCmd::GetPolicy(opts.get(1).cloned()),
+                "ports" => PatchbayCmd::ListPorts(opts.get(1).cloned()),
+                "clients" => PatchbayCmd::ListClients(opts.get(1).cloned()),
+                _ => return Err((ErrorCode::BadArgs, "Bad property!")),
+            },
+            "set" => match arg(opts, 0)? {
+                "policy" => match (enforcement(arg(opts, 1)?)?, opts.get(2)) {
+                    (None, None) => return Err((ErrorCode::BadArgs, "Bad policy!")),
+                    (policy, name) => PatchbayCmd::SetPolicy(policy, name.cloned()),
+                },
+                _ => return Err((ErrorCode::BadArgs, "Bad property!")),
+            },
+            "capture" => {
+                let (to, rest) = match arg(opts, 0)? {
+                    "show" => (CaptureTo::Show, &opts[1..]),
+                    "merge" => (CaptureTo::Merge, &opts[1..]),
+                    "save" => (CaptureTo::Save(arg(opts, 1)?.to_owned()), &opts[2..]),
+                    _ => return Err((ErrorCode::BadArgs, "Bad value!")),
+                };
+                let generalize = rest.iter().any(|o| o == "generalize");
+                let clients = rest.iter().filter(|o| *o != "generalize").cloned().collect();
+                PatchbayCmd::Capture(to, clients, generalize)
+            }
+            "merge" => PatchbayCmd::Merge(arg(opts, 0)?.to_owned()),
+            "mon" => PatchbayCmd::Monitor(patchbay_filter(opts.get(0))),
+            "subscribe" | "sub" => PatchbayCmd::Subscribe(patchbay_filter(opts.get(0))),
+            "unsubscribe" | "unsub" => PatchbayCmd::Unsubscribe(
+                arg(opts, 0)?
+                    .parse()
+                    .map_err(|_| (ErrorCode::BadArgs, "Bad value!"))?,
+            ),
+            _ => return Err((ErrorCode::BadCommand, "Bad command!")),
+        })
+    }
+}
+
+/// Daemon-wide commands (targets `all` and `cfg`)
+pub enum DaemonCmd {
+    Ping,
+    Version,
+    /// Dump of the mixer, the patchbay and the JACK server
+    GetState,
+    /// Handed over to the mixer, which owns the channels a load changes
+    Save,
+    Load,
+    Shutdown,
+}
+
+impl DaemonCmd {
+    pub fn parse(c: &Command) -> Result<Self, CmdError> {
+        let bad = Err((ErrorCode::BadCommand, "Bad command!"));
+
+        Ok(match c.cmd.as_str() {
+            "save" => DaemonCmd::Save,
+            "load" => DaemonCmd::Load,
+            _ if c.target == "cfg" => return bad,
+            "ping" => DaemonCmd::Ping,
+            "version" | "ver" => DaemonCmd::Version,
+            "get" => match c.opts.get(0).map(|o| o.as_str()) {
+                None | Some("state") | Some("all") => DaemonCmd::GetState,
+                Some("version") => DaemonCmd::Version,
+                Some(_) => return Err((ErrorCode::BadArgs, "Bad property!")),
+            },
+            "shutdown" | "quit" => DaemonCmd::Shutdown,
+            _ => return bad,
+        })
+    }
+}
@@ -5,14 +5,147 @@ extern crate slog;
 use std;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::Read;
-use std::net::TcpStream;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use utils::Connections;
 use utils::LogError;
 
+use events;
+use patterns;
 use server;
 
+/// Dynamic range (in dB) covered by the `db` fader law between 0% and 100%
+const DB_FADER_RANGE: f32 = 60.0;
+
+/// Mapping from fader position (`PortConfig::vol`, in percent) to gain
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FaderLaw {
+    /// The gain is the fader position
+    Linear,
+    /// The fader position is linear in dB, 0% being -inf
+    Db,
+    /// The gain is the cube of the fader position
+    Cubic,
+}
+
+impl Default for FaderLaw {
+    fn default() -> Self {
+        FaderLaw::Linear
+    }
+}
+
+impl FaderLaw {
+    /// Gain for a fader position given in percent
+    pub fn gain(&self, pos: f32) -> f32 {
+        let pos = pos / 100.0;
+        match *self {
+            FaderLaw::Linear => pos,
+            FaderLaw::Cubic => pos * pos * pos,
+            FaderLaw::Db => {
+                if pos <= 0.0 {
+                    0.0
+                } else {
+                    db_to_gain((pos - 1.0) * DB_FADER_RANGE)
+                }
+            }
+        }
+    }
+
+    /// Fader position (in percent) for a gain, inverse of `gain`
+    pub fn pos(&self, gain: f32) -> f32 {
+        100.0 * match *self {
+            FaderLaw::Linear => gain,
+            FaderLaw::Cubic => gain.cbrt(),
+            FaderLaw::Db => (1.0 + gain_to_db(gain) / DB_FADER_RANGE).max(0.0),
+        }
+    }
+
+    /// Parse a volume as given over IPC into a new fader position.
+    ///
+    /// Accepts an absolute position in percent (`80`), a relative step in
+    /// percent (`+5`, `-5`), an absolute level in dB (`-6dB`, `-inf`) or a
+    /// relative step in dB (`+=3dB`, `-=3dB`). NaN and infinite results are
+    /// refused, `-inf` dB being a gain of 0.
+    pub fn parse(&self, val: &str, cur_pos: f32) -> Option<f32> {
+        let val = val.trim();
+        let lower = val.to_lowercase();
+
+        if lower.ends_with("db") || lower.ends_with("inf") {
+            let num = if lower.ends_with("db") { &val[..val.len() - 2] } else { val };
+            let (rel, num) = if num.starts_with("+=") {
+                (1.0, &num[2..])
+            } else if num.starts_with("-=") {
+                (-1.0, &num[2..])
+            } else {
+                (0.0, num)
+            };
+            let db = parse_db(num.trim())?;
+
+            let db = if rel == 0.0 {
+                db
+            } else {
+                gain_to_db(self.gain(cur_pos)) + rel * db
+            };
+            Some(self.pos(db_to_gain(db)))
+        } else if val.starts_with('+') || val.starts_with('-') {
+            Some(cur_pos + val.parse::<f32>().ok()?)
+        } else {
+            val.parse().ok()
+        }.filter(|pos| pos.is_finite())
+    }
+}
+
+fn parse_db(val: &str) -> Option<f32> {
+    match val.to_lowercase().as_str() {
+        "-inf" => Some(std::f32::NEG_INFINITY),
+        v => v.parse().ok().filter(|db: &f32| db.is_finite()),
+    }
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.abs().log10()
+}
+
+/// Mapping from balance (-1 to 1, 1 being fully left) to the gains of the
+/// left and right sides
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PanLaw {
+    /// Unity at the center, the favored side gets up to +6dB
+    Balance,
+    /// -3dB at the center, unity on the favored side when fully panned
+    ConstantPower,
+    /// -6dB at the center, unity on the favored side when fully panned
+    Linear,
+}
+
+impl Default for PanLaw {
+    fn default() -> Self {
+        PanLaw::Balance
+    }
+}
+
+impl PanLaw {
+    pub fn gains(&self, b: f32) -> (f32, f32) {
+        match *self {
+            PanLaw::Balance => (b + 1.0, -b + 1.0),
+            PanLaw::ConstantPower => {
+                let theta = (1.0 - b.max(-1.0).min(1.0)) * std::f32::consts::FRAC_PI_4;
+                (theta.cos(), theta.sin())
+            }
+            PanLaw::Linear => ((1.0 + b) / 2.0, (1.0 - b) / 2.0),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PortConfig {
     #[serde(default = "PortConfig::default_vol")]
@@ -21,9 +154,28 @@ pub struct PortConfig {
     pub balance: f32,
     #[serde(default = "PortConfig::default_mono")]
     pub mono: bool,
+    #[serde(default = "PortConfig::default_mute")]
+    pub mute: bool,
+    #[serde(default = "PortConfig::default_solo")]
+    pub solo: bool,
+    /// Overrides the mixer-wide `pan_law` for this port
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pan_law: Option<PanLaw>,
 }
 
 impl PortConfig {
+    pub fn new(mono: bool) -> Self {
+        PortConfig {
+            vol: Self::default_vol(),
+            balance: Self::default_bal(),
+            mono,
+            mute: Self::default_mute(),
+            solo: Self::default_solo(),
+            pan_law: None,
+        }
+    }
+
     fn default_vol() -> f32 {
         100.0 as f32
     }
@@ -33,23 +185,53 @@ impl PortConfig {
     fn default_mono() -> bool {
         false
     }
-
-    pub fn get_vol(&self) -> f32 {
-        self.vol / 100.0
+    fn default_mute() -> bool {
+        false
+    }
+    fn default_solo() -> bool {
+        false
     }
 
     pub fn get_balance(&self) -> f32 {
         self.balance
     }
 
-    pub fn get_balance_pair(&self) -> (f32, f32) {
-        let b = self.get_balance();
-        (b + 1.0, -b + 1.0)
+    pub fn get_balance_pair(&self, default_law: PanLaw) -> (f32, f32) {
+        self.pan_law
+            .unwrap_or(default_law)
+            .gains(self.get_balance())
     }
 
     pub fn is_mono(&self) -> bool {
         self.mono
     }
+
+    pub fn is_muted(&self) -> bool {
+        self.mute
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.solo
+    }
+}
+
+/// Level of an input in the mix of an output (a crosspoint of the mixer's
+/// connection matrix)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendConfig {
+    #[serde(default = "PortConfig::default_vol")]
+    pub vol: f32,
+    #[serde(default = "PortConfig::default_mute")]
+    pub mute: bool,
+}
+
+impl Default for SendConfig {
+    fn default() -> Self {
+        SendConfig {
+            vol: PortConfig::default_vol(),
+            mute: PortConfig::default_mute(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,24 +240,73 @@ pub struct MonitorConfig {
     pub is_input: bool,
 }
 
+/// Input channels created on their own for JACK clients as they appear
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct AutoChannels {
+    /// Names (or patterns) of the clients that get a channel; none by default
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// Outputs the new channels are connected to
+    #[serde(default)]
+    pub buses: Vec<String>,
+    #[serde(default)]
+    pub mono: bool,
+    /// Keep the channel, and so its settings, when its client goes away
+    #[serde(default)]
+    pub keep: bool,
+}
+
+impl AutoChannels {
+    pub fn is_disabled(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MixerConfig {
     pub connections: HashMap<String, HashSet<String>>,
+    /// Send levels by output then input name; crosspoints that aren't listed
+    /// are at 100%
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub sends: HashMap<String, HashMap<String, SendConfig>>,
     pub outputs: HashMap<String, PortConfig>,
     pub inputs: HashMap<String, PortConfig>,
     pub monitor: MonitorConfig,
+    #[serde(default)]
+    pub fader_law: FaderLaw,
+    #[serde(default)]
+    pub pan_law: PanLaw,
+    /// Gain applied to each side of a stereo channel mixed into a mono one
+    #[serde(default = "MixerConfig::default_downmix")]
+    pub downmix: f32,
+    /// Time (in ms) over which volume and balance changes are faded in
+    #[serde(default = "MixerConfig::default_ramp_ms")]
+    pub ramp_ms: f32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "AutoChannels::is_disabled")]
+    pub auto: AutoChannels,
 
     // #[serde(skip)]
     // #[serde(default = "MixerConfig::get_default_hooks")]
     // o_vol_hooks: HashMap<String, Vec<TcpStream>>,
+    /// Clients waiting for changes to the mixer or the patchbay
     #[serde(skip)]
-    #[serde(default = "MixerConfig::get_default_hooks")]
-    mon_hooks: HashMap<String, HashMap<String, Vec<(TcpStream, slog::Logger)>>>,
+    #[serde(default = "MixerConfig::get_default_events")]
+    pub events: Arc<events::Events>,
 }
 
 impl MixerConfig {
-    pub fn get_default_hooks() -> HashMap<String, HashMap<String, Vec<(TcpStream, slog::Logger)>>> {
-        HashMap::new()
+    fn default_ramp_ms() -> f32 {
+        20.0
+    }
+
+    fn default_downmix() -> f32 {
+        1.0
+    }
+
+    pub fn get_default_events() -> Arc<events::Events> {
+        Arc::new(events::Events::new())
     }
 
     pub fn get_port_info(&self, is_output: bool, name: &String) -> Result<serde_json::Value, ()> {
@@ -86,29 +317,31 @@ impl MixerConfig {
         Ok(json!({
             "port":   &*name,
             "ptype":  if is_output {"out"} else {"in"},
-            "vol":    self.get_vol(is_output, name)?*100.0,
+            "vol":    self.get_fader(is_output, name)?,
+            "db":     json_db(gain_to_db(self.get_vol(is_output, name)?)),
             "bal":    self.get_bal(is_output, name)?,
+            "panlaw": self.get_pan_law(is_output, name)?,
             "ismono": self.get_mono(is_output, name)?,
+            "mute":   self.get_mute(is_output, name)?,
+            "solo":   self.get_solo(is_output, name)?,
             "cons":   self.get_connected(is_output, name)?,
         }))
     }
 
-    pub fn write_info_response(
-        &self,
-        is_output: bool,
-        name: &String,
-        stream: &mut TcpStream,
-        log: &slog::Logger,
-    ) {
-        server::write_response(
-            &log,
-            &server::Response {
-                ret: 0,
-                msg: &format!("{} info", name),
-                obj: self.get_port_info(is_output, name).unwrap(),
-            },
-            stream,
-        );
+    /// Port objects of every input and output channel
+    pub fn get_channels_info(&self) -> serde_json::Value {
+        let inputs: Vec<serde_json::Value> = self
+            .inputs
+            .keys()
+            .filter_map(|name| self.get_port_info(false, name).ok())
+            .collect();
+        let outputs: Vec<serde_json::Value> = self
+            .outputs
+            .keys()
+            .filter_map(|name| self.get_port_info(true, name).ok())
+            .collect();
+
+        json!({ "inputs": inputs, "outputs": outputs, })
     }
 
     pub fn get_mono(&self, is_output: bool, name: &String) -> Result<bool, ()> {
@@ -125,17 +358,19 @@ impl MixerConfig {
         .is_mono())
     }
 
+    /// Gain of a port according to its fader position and the fader law
     pub fn get_vol(&self, is_output: bool, name: &String) -> Result<f32, ()> {
-        if is_output {
-            match self.outputs.get(name) {
-                Some(o) => Ok(o.get_vol()),
-                None => Err(()),
-            }
-        } else {
-            match self.inputs.get(name) {
-                Some(i) => Ok(i.get_vol()),
-                None => Err(()),
-            }
+        Ok(self.fader_law.gain(self.get_fader(is_output, name)?))
+    }
+
+    /// Fader position of a port, in percent
+    pub fn get_fader(&self, is_output: bool, name: &String) -> Result<f32, ()> {
+        match match is_output {
+            true => self.outputs.get(name),
+            false => self.inputs.get(name),
+        } {
+            Some(p) => Ok(p.vol),
+            None => Err(()),
         }
     }
 
@@ -149,24 +384,7 @@ impl MixerConfig {
         }.get_mut(name)
         .unwrap()
         .vol = vol;
-        if let Some(mut hs) = self
-            .mon_hooks
-            .entry(if is_output {
-                "output_vol".to_owned()
-            } else {
-                "input_vol".to_owned()
-            }).or_insert(HashMap::new())
-            .remove(name)
-        {
-            // let msg = format!("{}", vol);
-            for &mut (ref mut stream, ref log) in hs.iter_mut() {
-                self.write_info_response(is_output, name, stream, log)
-                // let _ = stream.write(msg.as_bytes());
-                // let _ = stream.write(b"\n");
-                // let _ = stream.flush();
-            }
-            hs.clear();
-        }
+        self.fire_info_hooks("volume", is_output, name);
         Ok(())
     }
 
@@ -199,76 +417,93 @@ impl MixerConfig {
         }
     }
 
-    pub fn hook(&mut self, h_name: String, name: String, stream: TcpStream, log: slog::Logger) {
-        self.mon_hooks
-            .entry(h_name)
-            .or_insert(HashMap::new())
-            .entry(name)
-            .or_insert(Vec::new())
-            .push((stream, log));
-    }
-
     pub fn connect(&mut self, connecting: bool, oname: &str, iname: &str) -> Result<(), ()> {
         self.connections.connect(connecting, oname, iname);
-        if let Some(hs) = self
-            .mon_hooks
-            .entry("output_con".to_owned())
-            .or_insert(HashMap::new())
-            .get_mut(oname)
-        {
-            // let msg = format!("{}connection to: {}",
-            //                   if connecting {""} else {"dis"}, iname);
-            let response = server::Response {
-                ret: 0,
-                msg: &format!("{}connection", if connecting { "" } else { "dis" }),
-                obj: json!({
-                    "output_name": oname,
-                    "input_name": iname,
-                }),
-            };
+        self.events.emit(&events::Event {
+            property: "connections",
+            ports: vec![(true, oname.to_owned()), (false, iname.to_owned())],
+            msg: &format!("{}connection", if connecting { "" } else { "dis" }),
+            obj: json!({
+                "output_name": oname,
+                "input_name": iname,
+            }),
+            closes: false,
+        });
+        Ok(())
+    }
 
-            for &mut (ref mut stream, ref log) in hs.iter_mut() {
-                server::write_response(log, &response, stream);
-            }
-            // for stream in hs.iter_mut() {
-            //     let _ = stream.write(msg.as_bytes());
-            //     let _ = stream.write(b"\n");
-            //     let _ = stream.flush();
-            // }
-            hs.clear();
-        }
-        if let Some(hs) = self
-            .mon_hooks
-            .entry("input_con".to_owned())
-            .or_insert(HashMap::new())
-            .get_mut(iname)
-        {
-            let response = server::Response {
-                ret: 0,
-                msg: &format!("{}connection", if connecting { "" } else { "dis" }),
-                obj: json!({
-                    "output_name": oname,
-                    "input_name": iname,
-                }),
-            };
+    pub fn is_connected(&self, oname: &str, iname: &str) -> bool {
+        self.connections.is_connected(oname, iname)
+    }
 
-            for &mut (ref mut stream, ref log) in hs.iter_mut() {
-                server::write_response(log, &response, stream);
-            }
-            // let msg = format!("{}connection to: {}",
-            //                   if connecting {""} else {"dis"}, oname);
-            // for stream in hs.iter_mut() {
-            //     let _ = stream.write(msg.as_bytes());
-            //     let _ = stream.write(b"\n");
-            //     let _ = stream.flush();
-            // }
-            hs.clear();
+    pub fn get_send(&self, oname: &String, iname: &String) -> Result<SendConfig, ()> {
+        if !self.port_exists(true, oname) || !self.port_exists(false, iname) {
+            return Err(());
+        }
+        Ok(self
+            .sends
+            .get(oname)
+            .and_then(|is| is.get(iname))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Gain of an input in the mix of an output, on top of the gains of both
+    pub fn get_send_gain(&self, oname: &String, iname: &String) -> Result<f32, ()> {
+        let send = self.get_send(oname, iname)?;
+        Ok(if send.mute {
+            0.0
+        } else {
+            self.fader_law.gain(send.vol)
+        })
+    }
+
+    fn send_mut(&mut self, oname: &String, iname: &String) -> Result<&mut SendConfig, ()> {
+        if !self.port_exists(true, oname) || !self.port_exists(false, iname) {
+            return Err(());
         }
+        Ok(self
+            .sends
+            .entry(oname.clone())
+            .or_insert(HashMap::new())
+            .entry(iname.clone())
+            .or_insert(SendConfig::default()))
+    }
+
+    pub fn set_send_vol(&mut self, oname: &String, iname: &String, vol: f32) -> Result<(), ()> {
+        self.send_mut(oname, iname)?.vol = vol;
+        self.fire_send_hooks(oname, iname);
         Ok(())
     }
 
-    pub fn is_connected(&self, oname: &str, iname: &str) -> bool {
-        self.connections.is_connected(oname, iname)
+    pub fn set_send_mute(&mut self, oname: &String, iname: &String, mute: bool) -> Result<(), ()> {
+        self.send_mut(oname, iname)?.mute = mute;
+        self.fire_send_hooks(oname, iname);
+        Ok(())
+    }
+
+    fn fire_send_hooks(&self, oname: &String, iname: &String) {
+        if let Ok(obj) = self.get_send_info(oname, iname) {
+            self.events.emit(&events::Event {
+                property: "send",
+                ports: vec![(true, oname.clone()), (false, iname.clone())],
+                msg: "send",
+                obj,
+                closes: false,
+            });
+        }
+    }
+
+    pub fn get_send_info(&self, oname: &String, iname: &String) -> Result<serde_json::Value, ()> {
+        let send = self.get_send(oname, iname)?;
+        Ok(json!({
+            "output":    &*oname,
+            "input":     &*iname,
+            "vol":       send.vol,
+            "db":        json_db(gain_to_db(self.fader_law.gain(send.vol))),
+            "mute":      send.mute,
+            "connected": self.is_connected(oname, iname),
+        }))
     }
 
     pub fn set_bal(&mut self, is_output: bool, pname: &String, balance: f32) -> Result<(), ()> {
@@ -282,26 +517,7 @@ impl MixerConfig {
         }.get_mut(pname)
         .unwrap()
         .balance = balance;
-        if let Some(mut hs) = self
-            .mon_hooks
-            .entry(if is_output {
-                "output_bal".to_owned()
-            } else {
-                "input_bal".to_owned()
-            }).or_insert(HashMap::new())
-            .remove(pname)
-        {
-            for &mut (ref mut stream, ref log) in hs.iter_mut() {
-                self.write_info_response(is_output, pname, stream, log)
-            }
-            // let msg = format!("{}", balance);
-            // for stream in hs.iter_mut() {
-            //     let _ = stream.write(msg.as_bytes());
-            //     let _ = stream.write(b"\n");
-            //     let _ = stream.flush();
-            // }
-            hs.clear();
-        }
+        self.fire_info_hooks("balance", is_output, pname);
         Ok(())
     }
 
@@ -315,61 +531,837 @@ impl MixerConfig {
         }
     }
 
+    pub fn get_pan_law(&self, is_output: bool, name: &String) -> Result<PanLaw, ()> {
+        match match is_output {
+            true => self.outputs.get(name),
+            false => self.inputs.get(name),
+        } {
+            Some(p) => Ok(p.pan_law.unwrap_or(self.pan_law)),
+            None => Err(()),
+        }
+    }
+
     pub fn get_bal_pair(&self, is_output: bool, name: &String) -> Result<(f32, f32), ()> {
         match match is_output {
             true => self.outputs.get(name),
             false => self.inputs.get(name),
         } {
-            Some(p) => Ok(p.get_balance_pair()),
+            Some(p) => Ok(p.get_balance_pair(self.pan_law)),
+            None => Err(()),
+        }
+    }
+
+    pub fn get_mute(&self, is_output: bool, name: &String) -> Result<bool, ()> {
+        match match is_output {
+            true => self.outputs.get(name),
+            false => self.inputs.get(name),
+        } {
+            Some(p) => Ok(p.is_muted()),
             None => Err(()),
         }
     }
 
+    pub fn set_mute(&mut self, is_output: bool, name: &String, mute: bool) -> Result<(), ()> {
+        if !self.port_exists(is_output, name) {
+            return Err(());
+        }
+        match is_output {
+            true => &mut self.outputs,
+            false => &mut self.inputs,
+        }.get_mut(name)
+        .unwrap()
+        .mute = mute;
+        self.fire_info_hooks("mute", is_output, name);
+        Ok(())
+    }
+
+    pub fn get_solo(&self, is_output: bool, name: &String) -> Result<bool, ()> {
+        match match is_output {
+            true => self.outputs.get(name),
+            false => self.inputs.get(name),
+        } {
+            Some(p) => Ok(p.is_soloed()),
+            None => Err(()),
+        }
+    }
+
+    pub fn set_solo(&mut self, is_output: bool, name: &String, solo: bool) -> Result<(), ()> {
+        if !self.port_exists(is_output, name) {
+            return Err(());
+        }
+        match is_output {
+            true => &mut self.outputs,
+            false => &mut self.inputs,
+        }.get_mut(name)
+        .unwrap()
+        .solo = solo;
+        self.fire_info_hooks("solo", is_output, name);
+        Ok(())
+    }
+
+    /// Whether any port on the given side of the mixer is soloed
+    pub fn any_solo(&self, is_output: bool) -> bool {
+        match is_output {
+            true => &self.outputs,
+            false => &self.inputs,
+        }.values()
+        .any(|p| p.is_soloed())
+    }
+
+    /// Whether a port is audible, taking its own mute and the solo state of
+    /// its side of the mixer into account
+    pub fn is_audible(&self, is_output: bool, name: &String) -> Result<bool, ()> {
+        let muted = self.get_mute(is_output, name)?;
+        let soloed = self.get_solo(is_output, name)?;
+        Ok(!muted && (soloed || !self.any_solo(is_output)))
+    }
+
+    /// Volume of a port as it should be applied to the signal, i.e. 0 when
+    /// the port is muted or silenced by another port's solo
+    pub fn get_gain(&self, is_output: bool, name: &String) -> Result<f32, ()> {
+        if self.is_audible(is_output, name)? {
+            self.get_vol(is_output, name)
+        } else {
+            Ok(0.0)
+        }
+    }
+
+    /// Tell the clients waiting for changes to a property of a port about
+    /// its new state
+    fn fire_info_hooks(&self, property: &str, is_output: bool, name: &String) {
+        if let Ok(obj) = self.get_port_info(is_output, name) {
+            self.events.emit(&events::Event {
+                property,
+                ports: vec![(is_output, name.clone())],
+                msg: &format!("{} info", name),
+                obj,
+                closes: false,
+            });
+        }
+    }
+
     pub fn set_mon(&mut self, is_output: bool, name: &String) -> Result<(), ()> {
         if !self.port_exists(is_output, name) {
             return Err(());
         }
         self.monitor.channel = name.to_owned();
         self.monitor.is_input = !is_output;
+        self.fire_info_hooks("monitor", is_output, name);
+        Ok(())
+    }
+
+    fn is_monitored(&self, is_output: bool, name: &String) -> bool {
+        &self.monitor.channel == name && self.monitor.is_input == !is_output
+    }
+
+    pub fn add_port(&mut self, is_output: bool, name: &String, mono: bool) -> Result<(), ()> {
+        if self.port_exists(is_output, name) {
+            return Err(());
+        }
+        match is_output {
+            true => &mut self.outputs,
+            false => &mut self.inputs,
+        }.insert(name.clone(), PortConfig::new(mono));
+        self.notify_port_event("create", is_output, name, None);
+        Ok(())
+    }
+
+    /// Remove a port along with its connections and sends. If it was being
+    /// monitored, the monitor falls back to another port.
+    pub fn remove_port(&mut self, is_output: bool, name: &String) -> Result<(), ()> {
+        if match is_output {
+            true => &mut self.outputs,
+            false => &mut self.inputs,
+        }.remove(name)
+        .is_none()
+        {
+            return Err(());
+        }
+
+        if is_output {
+            self.connections.remove(name);
+            self.sends.remove(name);
+        } else {
+            for is in self.connections.values_mut() {
+                is.remove(name);
+            }
+            self.connections.retain(|_, is| !is.is_empty());
+            for is in self.sends.values_mut() {
+                is.remove(name);
+            }
+            self.sends.retain(|_, is| !is.is_empty());
+        }
+
+        if self.is_monitored(is_output, name) {
+            let fallback = match self.outputs.keys().min() {
+                Some(o) => Some((true, o.clone())),
+                None => self.inputs.keys().min().map(|i| (false, i.clone())),
+            };
+            if let Some((is_output, fallback)) = fallback {
+                self.set_mon(is_output, &fallback)?;
+            }
+        }
+
+        self.notify_port_event("delete", is_output, name, None);
         Ok(())
     }
+
+    pub fn rename_port(&mut self, is_output: bool, name: &String, new_name: &String) -> Result<(), ()> {
+        if !self.port_exists(is_output, name) || self.port_exists(is_output, new_name) {
+            return Err(());
+        }
+
+        let ports = match is_output {
+            true => &mut self.outputs,
+            false => &mut self.inputs,
+        };
+        let port = ports.remove(name).unwrap();
+        ports.insert(new_name.clone(), port);
+
+        if is_output {
+            if let Some(is) = self.connections.remove(name) {
+                self.connections.insert(new_name.clone(), is);
+            }
+            if let Some(is) = self.sends.remove(name) {
+                self.sends.insert(new_name.clone(), is);
+            }
+        } else {
+            for is in self.connections.values_mut() {
+                if is.remove(name) {
+                    is.insert(new_name.clone());
+                }
+            }
+            for is in self.sends.values_mut() {
+                if let Some(send) = is.remove(name) {
+                    is.insert(new_name.clone(), send);
+                }
+            }
+        }
+
+        if self.is_monitored(is_output, name) {
+            self.monitor.channel = new_name.clone();
+        }
+
+        self.notify_port_event("rename", is_output, name, Some(new_name));
+        Ok(())
+    }
+
+    /// Tell `ports` monitors about a created, deleted or renamed port, and
+    /// release the monitors of properties of a port that no longer exists
+    /// under that name
+    fn notify_port_event(
+        &self,
+        event: &str,
+        is_output: bool,
+        name: &String,
+        new_name: Option<&String>,
+    ) {
+        let obj = json!({
+            "event":    event,
+            "ptype":    if is_output {"out"} else {"in"},
+            "port":     &*name,
+            "new_name": new_name,
+        });
+
+        let mut ports = vec![(is_output, name.clone())];
+        ports.extend(new_name.map(|n| (is_output, n.clone())));
+        self.events.emit(&events::Event {
+            property: "ports",
+            ports,
+            msg: &format!("port {}", event),
+            obj,
+            closes: event != "create",
+        });
+    }
+}
+
+/// dB value as sent to clients; JSON has no infinities so -inf becomes null
+fn json_db(db: f32) -> serde_json::Value {
+    if db.is_finite() {
+        json!(db)
+    } else {
+        serde_json::Value::Null
+    }
+}
+
+/// What a client holding a token is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Only commands that don't change anything (`get`, `subscribe`, ...)
+    ReadOnly,
+    /// Every command
+    Full,
+}
+
+/// How a (dis)connection JACK refused is tried again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryPolicy {
+    /// Delay before the first retry, in ms
+    #[serde(default = "RetryPolicy::default_delay_ms")]
+    pub delay_ms: u64,
+    /// Factor the delay grows by with every failed attempt
+    #[serde(default = "RetryPolicy::default_backoff")]
+    pub backoff: f32,
+    /// Longest delay between two attempts, in ms
+    #[serde(default = "RetryPolicy::default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Attempts after which to give up, 0 to never give up
+    #[serde(default)]
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            delay_ms: Self::default_delay_ms(),
+            backoff: Self::default_backoff(),
+            max_delay_ms: Self::default_max_delay_ms(),
+            max_attempts: 0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn default_delay_ms() -> u64 {
+        100
+    }
+
+    fn default_backoff() -> f32 {
+        2.0
+    }
+
+    fn default_max_delay_ms() -> u64 {
+        10000
+    }
+
+    /// Delay before retrying after the given number of failed attempts, or
+    /// `None` to give up
+    pub fn delay(&self, attempts: u32) -> Option<Duration> {
+        if self.max_attempts != 0 && attempts >= self.max_attempts {
+            return None;
+        }
+        let factor = self.backoff.max(1.0).powi(attempts.saturating_sub(1) as i32);
+        let ms = (self.delay_ms as f32 * factor).min(self.max_delay_ms as f32);
+        Some(Duration::from_millis(ms as u64))
+    }
+}
+
+/// Retry policies of the patchbay
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub default: RetryPolicy,
+    /// Policies for the rules with the given output side, which also apply
+    /// to the ports it matches
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub rules: HashMap<String, RetryPolicy>,
+
+    /// The patterns of `rules`, by precedence
+    #[serde(skip)]
+    ranked: patterns::Ranked,
+}
+
+impl RetryConfig {
+    /// Compile the patterns of the rules, after they were loaded or changed
+    pub fn compile(&mut self) {
+        self.ranked = patterns::Ranked::new(self.rules.keys());
+    }
+
+    /// Policy for the connections of an output port: the one for its exact
+    /// name, else the one for the pattern matching it with the longest
+    /// literal prefix
+    pub fn policy(&self, oname: &str) -> &RetryPolicy {
+        self.rules
+            .get(oname)
+            .or_else(|| self.ranked.find(&[oname]).and_then(|pat| self.rules.get(pat)))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// How strictly the patchbay holds ports to the rules
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+    /// Only the connections the rules want are allowed
+    Strict,
+    /// The connections the rules want are made, others are left alone
+    Additive,
+    /// The ports aren't managed at all
+    Ignore,
+}
+
+impl Default for Enforcement {
+    fn default() -> Self {
+        Enforcement::Strict
+    }
+}
+
+/// Enforcement policies of the patchbay
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EnforcementConfig {
+    #[serde(default)]
+    pub default: Enforcement,
+    /// Policies for the ports or clients matching a name or a pattern
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub policies: HashMap<String, Enforcement>,
+
+    /// The patterns of `policies`, by precedence
+    #[serde(skip)]
+    ranked: patterns::Ranked,
+}
+
+impl EnforcementConfig {
+    /// Compile the patterns of the policies, after they were loaded
+    pub fn compile(&mut self) {
+        self.ranked = patterns::Ranked::new(self.policies.keys());
+    }
+
+    /// Set or, with `None`, remove the policy for a name or a pattern
+    pub fn set(&mut self, name: String, policy: Option<Enforcement>) {
+        match policy {
+            Some(policy) => {
+                self.policies.insert(name, policy);
+            }
+            None => {
+                self.policies.remove(&name);
+            }
+        }
+        self.compile();
+    }
+
+    /// Policy set for a port, if any: the one for its exact name, else for
+    /// its client's, else for the pattern matching either with the longest
+    /// literal prefix
+    pub fn policy_of(&self, port_name: &str) -> Option<Enforcement> {
+        let client = port_name.split(':').next().unwrap_or("");
+        self.policies
+            .get(port_name)
+            .or_else(|| self.policies.get(client))
+            .or_else(|| {
+                self.ranked
+                    .find(&[port_name, client])
+                    .and_then(|pat| self.policies.get(pat))
+            }).cloned()
+    }
+
+    /// Policy for the connection of two ports: the output's, else the
+    /// input's, else the default
+    pub fn policy(&self, oname: &str, iname: &str) -> Enforcement {
+        self.policy_of(oname)
+            .or_else(|| self.policy_of(iname))
+            .unwrap_or(self.default)
+    }
+}
+
+/// Where the IPC server listens for clients and who it accepts commands from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    /// TCP addresses to listen on, as `ADDR:PORT`, `ADDR` or `PORT`
+    #[serde(default = "ServerConfig::default_listen")]
+    pub listen: Vec<String>,
+    /// Path of the Unix socket, `$XDG_RUNTIME_DIR/jamyx.sock` if not set;
+    /// an empty path disables the Unix socket
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+    /// Tokens clients have to present, and what each of them allows; when
+    /// there are none, every client has full access
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub tokens: HashMap<String, Access>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: Self::default_listen(),
+            socket: None,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn default_listen() -> Vec<String> {
+        vec![format!("127.0.0.1:{}", server::DEFAULT_PORT)]
+    }
+
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        match self.socket {
+            Some(ref path) if path.is_empty() => None,
+            Some(ref path) => Some(PathBuf::from(path)),
+            None => server::default_socket_path(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub connections: HashMap<String, HashSet<String>>,
+    /// Client-level rules: the outputs of a client (name or pattern) to the
+    /// inputs of clients or mixer channels, paired in order
+    #[serde(default)]
+    pub clients: HashMap<String, HashSet<String>>,
+    /// Connections that are never allowed, in the form of `connections`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub forbidden: HashMap<String, HashSet<String>>,
+    /// Groups of alternative routes, in the form of `connections`, by
+    /// priority: only the first one whose input ports are all there is used
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<Vec<HashMap<String, HashSet<String>>>>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub enforcement: EnforcementConfig,
     pub mixer: MixerConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+
+    /// File the config was last loaded from or saved to
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+
+    /// `connections` and `forbidden`, compiled
+    #[serde(skip)]
+    wanted_rules: patterns::Rules,
+    #[serde(skip)]
+    forbidden_rules: patterns::Rules,
+}
+
+impl Config {
+    /// Replace the config with a freshly loaded one, keeping the monitors
+    /// that are waiting on the current one
+    pub fn replace(&mut self, mut new: Config) {
+        new.mixer.events = self.mixer.events.clone();
+        *self = new;
+    }
+
+    /// Compile the patterns the rules and the policies are looked up by
+    pub fn compile(&mut self) {
+        self.compile_rules();
+        self.retry.compile();
+        self.enforcement.compile();
+    }
+
+    /// Compile `connections` and `forbidden`, after they were changed
+    pub fn compile_rules(&mut self) {
+        self.wanted_rules = patterns::Rules::new(&self.connections);
+        self.forbidden_rules = patterns::Rules::new(&self.forbidden);
+    }
+
+    /// Whether the rules have an output port connected to an input port
+    pub fn wants(&self, oname: &str, iname: &str) -> bool {
+        self.wanted_rules.wants(oname, iname)
+    }
+
+    /// Whether the connection of two ports is forbidden by a rule
+    pub fn forbids(&self, oname: &str, iname: &str) -> bool {
+        self.forbidden_rules.wants(oname, iname)
+    }
+
+    /// Write the config to `path` (by default the one it came from).
+    ///
+    /// The file is written next to its destination first and then moved in
+    /// place, so that a crash never leaves a half-written config behind.
+    pub fn save(&mut self, path: Option<&Path>, logger: &slog::Logger) -> Result<PathBuf, ()> {
+        let path = match path.map(|p| p.to_owned()).or_else(|| self.path.clone()) {
+            Some(path) => path,
+            None => {
+                error!(logger, "No path to save the config to");
+                return Err(());
+            }
+        };
+        info!(logger, "Saving config file at path: {:?}", path);
+
+        let s = serde_json::to_string_pretty(self).log_err(logger).map_err(|_| ())?;
+        write_file(&path, &s, logger)?;
+
+        self.path = Some(path.clone());
+        Ok(path)
+    }
+
+    /// Follow a renamed JACK port in the patchbay connections
+    pub fn rename_jack_port(&mut self, name: &str, new_name: &str) {
+        let mut rule_sets = vec![&mut self.connections, &mut self.forbidden];
+        rule_sets.extend(self.fallbacks.iter_mut().flat_map(|group| group.iter_mut()));
+        for rules in rule_sets {
+            if let Some(is) = rules.remove(name) {
+                rules
+                    .entry(new_name.to_owned())
+                    .or_insert(HashSet::new())
+                    .extend(is);
+            }
+            for is in rules.values_mut() {
+                if is.remove(name) {
+                    is.insert(new_name.to_owned());
+                }
+            }
+        }
+
+        // Client rules name mixer channels without the side of the port
+        let channel = |n: &str| n.rsplitn(2, ' ').nth(1).map(|c| c.to_owned());
+        for dsts in self.clients.values_mut() {
+            if dsts.remove(name) {
+                dsts.insert(new_name.to_owned());
+            }
+            if let (Some(old), Some(new)) = (channel(name), channel(new_name)) {
+                if dsts.remove(&old) {
+                    dsts.insert(new);
+                }
+            }
+        }
+        self.compile_rules();
+    }
+}
+
+/// Write `s` to `path`, next to its destination first and then moved in place
+fn write_file(path: &Path, s: &str, logger: &slog::Logger) -> Result<(), ()> {
+    let mut tmp = path.to_owned().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(s.as_bytes())?;
+            file.write_all(b"\n")?;
+            file.sync_all()
+        }).and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|why| {
+            error!(
+                logger,
+                "couldn't write {}: {}",
+                path.display(),
+                why.description()
+            );
+            let _ = std::fs::remove_file(&tmp);
+        })
+}
+
+/// Write patchbay rules to a profile file, holding only a `connections`
+/// object in the form of the config file's, to be read with `load_profile`
+pub fn save_profile(
+    path: &Path,
+    connections: &HashMap<String, HashSet<String>>,
+    logger: &slog::Logger,
+) -> Result<(), ()> {
+    info!(logger, "Saving patchbay profile at path: {:?}", path);
+    let s = serde_json::to_string_pretty(&json!({ "connections": connections }))
+        .log_err(logger)
+        .map_err(|_| ())?;
+    write_file(path, &s, logger)
+}
+
+/// Reply with the info of a channel, read under the config lock and written
+/// once it is released
+pub fn write_info_response(
+    cfg: &RwLock<Config>,
+    is_output: bool,
+    name: &String,
+    stream: &mut server::Stream,
+    log: &slog::Logger,
+) {
+    let info = cfg.read().unwrap().mixer.get_port_info(is_output, name);
+    let msg = format!("{} info", name);
+    server::write_response(
+        &log,
+        &match info {
+            Ok(obj) => server::Response {
+                ret: server::ErrorCode::Ok as i32,
+                msg: &msg,
+                obj,
+            },
+            Err(_) => server::Response::error(server::ErrorCode::NotFound, "Port not found!"),
+        },
+        stream,
+    );
 }
 
 pub fn parse(path: &str, logger: slog::Logger) -> Config {
-    let path = std::path::Path::new(path);
-    info!(logger, "Parsing config file at path: {:?}", path);
+    match load(Path::new(path), &logger) {
+        Ok(config) => config,
+        Err(_) => {
+            crit!(logger, "couldn't load config file");
+            panic!();
+        }
+    }
+}
 
+/// Read the file at `path` into a string
+fn read_file(path: &Path, logger: &slog::Logger) -> Result<String, ()> {
     let mut file = match std::fs::File::open(&path) {
         Err(why) => {
-            crit!(
+            error!(
                 logger,
                 "couldn't open {}: {}",
                 path.display(),
                 why.description()
             );
-            panic!();
+            return Err(());
         }
         Ok(file) => file,
     };
 
     let mut s = String::new();
     if let Err(why) = file.read_to_string(&mut s) {
-        crit!(
+        error!(
             logger,
             "couldn't read {}: {}",
             path.display(),
             why.description()
         );
-        panic!();
+        return Err(());
+    }
+    Ok(s)
+}
+
+/// Read and parse the config file at `path`, without panicking
+pub fn load(path: &Path, logger: &slog::Logger) -> Result<Config, ()> {
+    info!(logger, "Parsing config file at path: {:?}", path);
+
+    let s = read_file(path, logger)?;
+    let mut config: Config = serde_json::from_str(&s).log_err(logger).map_err(|_| ())?;
+    config.path = Some(path.to_owned());
+    config.compile();
+
+    Ok(config)
+}
+
+/// The patchbay rules of a profile file; the `connections` of a whole
+/// config file are read as well
+pub fn load_profile(path: &Path, logger: &slog::Logger) -> Result<HashMap<String, HashSet<String>>, ()> {
+    info!(logger, "Parsing patchbay profile at path: {:?}", path);
+    let s = read_file(path, logger)?;
+    let mut profile: serde_json::Value = serde_json::from_str(&s).log_err(logger).map_err(|_| ())?;
+    let connections = match profile.get_mut("connections") {
+        Some(connections) => std::mem::replace(connections, serde_json::Value::Null),
+        None => {
+            error!(logger, "no patchbay rules in {}", path.display());
+            return Err(());
+        }
+    };
+    serde_json::from_value(connections).log_err(logger).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Option<f32>, b: f32) -> bool {
+        a.map_or(false, |a| (a - b).abs() < 1e-3)
+    }
+
+    #[test]
+    fn fader_parses_percent() {
+        assert_eq!(FaderLaw::Linear.parse("80", 50.0), Some(80.0));
+        assert_eq!(FaderLaw::Linear.parse(" 80 ", 50.0), Some(80.0));
+        assert_eq!(FaderLaw::Linear.parse("+5", 50.0), Some(55.0));
+        assert_eq!(FaderLaw::Linear.parse("-5", 50.0), Some(45.0));
+        assert_eq!(FaderLaw::Linear.parse("loud", 50.0), None);
+    }
+
+    #[test]
+    fn fader_parses_db() {
+        // Absolute levels don't depend on the current position
+        assert!(close(FaderLaw::Linear.parse("-6dB", 10.0), 100.0 * db_to_gain(-6.0)));
+        assert!(close(FaderLaw::Linear.parse("0db", 10.0), 100.0));
+        assert!(close(FaderLaw::Db.parse("-6dB", 10.0), 90.0));
+        assert!(close(FaderLaw::Db.parse("-60dB", 10.0), 0.0));
+        assert!(close(FaderLaw::Cubic.parse("0dB", 10.0), 100.0));
+
+        // Relative steps do
+        assert!(close(FaderLaw::Db.parse("+=3dB", 50.0), 55.0));
+        assert!(close(FaderLaw::Db.parse("-=6dB", 50.0), 40.0));
+        assert!(close(FaderLaw::Linear.parse("-=6dB", 100.0), 100.0 * db_to_gain(-6.0)));
+    }
+
+    #[test]
+    fn fader_parses_silence() {
+        for law in &[FaderLaw::Linear, FaderLaw::Db, FaderLaw::Cubic] {
+            assert_eq!(law.parse("-inf", 50.0), Some(0.0));
+            assert_eq!(law.parse("-INFdB", 50.0), Some(0.0));
+        }
+    }
+
+    #[test]
+    fn fader_refuses_non_finite() {
+        for val in &["nan", "inf", "+inf", "infdB", "NaNdB", "+=infdB", "+nan", "1e40", "+=1e40dB"] {
+            assert_eq!(FaderLaw::Linear.parse(val, 50.0), None, "{} should be refused", val);
+        }
+    }
+
+    fn ms(delay: Option<Duration>) -> Option<u64> {
+        delay.map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+    }
+
+    #[test]
+    fn retry_backs_off_up_to_the_max_delay() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<Option<u64>> = (1..10).map(|a| ms(policy.delay(a))).collect();
+        assert_eq!(
+            delays,
+            vec![100, 200, 400, 800, 1600, 3200, 6400, 10000, 10000]
+                .into_iter()
+                .map(Some)
+                .collect::<Vec<_>>()
+        );
+        // Even after long enough for the factor to overflow
+        assert_eq!(ms(policy.delay(1000)), Some(10000));
+    }
+
+    #[test]
+    fn retry_backoff_never_shrinks_the_delay() {
+        let policy = RetryPolicy {
+            backoff: 0.5,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(ms(policy.delay(1)), Some(100));
+        assert_eq!(ms(policy.delay(5)), Some(100));
+    }
+
+    #[test]
+    fn retry_gives_up_after_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(ms(policy.delay(1)), Some(100));
+        assert_eq!(ms(policy.delay(2)), Some(200));
+        assert_eq!(policy.delay(3), None);
+        assert_eq!(policy.delay(4), None);
+
+        // 0 is never
+        assert!(RetryPolicy::default().delay(u32::max_value()).is_some());
+    }
+
+    #[test]
+    fn profile_round_trips() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let path = std::env::temp_dir().join(format!("jamyx-profile-{}.json", std::process::id()));
+
+        let mut rules = HashMap::new();
+        rules.connect(true, "system:capture_1", "Jacon:MIC L");
+        rules.connect(true, "system:capture_1", "recorder:in_1");
+        rules.connect(true, "alsa-jack.jackP.*.0:out_000", "Jacon:PulseSink L");
+
+        save_profile(&path, &rules, &log).unwrap();
+        let loaded = load_profile(&path, &log);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, Ok(rules));
     }
 
-    let config: Config = serde_json::from_str(&s).log_err(&logger).unwrap();
+    #[test]
+    fn profile_refuses_files_without_rules() {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let path = std::env::temp_dir().join(format!("jamyx-not-a-profile-{}.json", std::process::id()));
 
-    return config;
+        write_file(&path, "{\"mixer\": {}}", &log).unwrap();
+        let loaded = load_profile(&path, &log);
+        let _ = std::fs::remove_file(&path);
+        assert!(loaded.is_err());
+    }
 }
@@ -0,0 +1,185 @@
+extern crate jam;
+extern crate slog;
+
+use std;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use command::DaemonCmd;
+use config;
+use jacon;
+use server;
+
+use utils::LogError;
+
+type AM<T> = Arc<Mutex<T>>;
+type AMAnyClient = AM<jam::AnyClient>;
+
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Optional parts of the protocol this server speaks, for clients to check
+/// before relying on them
+static CAPABILITIES: &[&str] = &[
+    "ids",
+    "auth",
+    "error_codes",
+    "subscribe",
+    "meters",
+    "channels",
+    "save",
+    "load",
+    "state",
+    "shutdown",
+];
+
+/// Handles the commands that concern the whole server rather than just the
+/// mixer (`Patchbay`) or the patchbay (`ConnectionKit`)
+pub struct Daemon {
+    log: slog::Logger,
+    cli: AMAnyClient,
+    cfg: Arc<RwLock<config::Config>>,
+    failures: jacon::Failures,
+    t_cmd: Option<Sender<(server::Stream, server::Command)>>,
+    cmd_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Daemon {
+    pub fn new(
+        log: slog::Logger,
+        cli: AMAnyClient,
+        cfg: Arc<RwLock<config::Config>>,
+        failures: jacon::Failures,
+    ) -> Self {
+        Daemon {
+            log,
+            cli,
+            cfg,
+            failures,
+            t_cmd: None,
+            cmd_thread: None,
+        }
+    }
+
+    /// `t_myx` gets the commands only the mixer can carry out, `t_quit` is
+    /// signaled on `shutdown` once the client got its reply
+    pub fn start(&mut self, t_myx: Sender<(server::Stream, server::Command)>, t_quit: Sender<()>) {
+        let (_t_cmd, r_cmd) = channel();
+        let cfg = self.cfg.clone();
+        let cli = self.cli.clone();
+        let failures = self.failures.clone();
+        let log = self.log.clone();
+
+        self.t_cmd = Some(_t_cmd.clone());
+        self.cmd_thread = Some(thread::spawn(move || loop {
+            let (mut stream, command): (server::Stream, server::Command) = r_cmd.recv().unwrap();
+            let parsed = match DaemonCmd::parse(&command) {
+                Ok(parsed) => parsed,
+                Err((code, msg)) => {
+                    warn!(log, "{} ({} `{}` {:?})", msg, command.target, command.cmd, command.opts);
+                    server::write_response(&log, &server::Response::error(code, msg), &mut stream);
+                    continue;
+                }
+            };
+
+            match parsed {
+                DaemonCmd::Ping => {
+                    server::write_response(
+                        &log,
+                        &server::Response {
+                            ret: server::ErrorCode::Ok as i32,
+                            msg: "pong",
+                            obj: json!({}),
+                        },
+                        &mut stream,
+                    );
+                }
+                DaemonCmd::Version => {
+                    server::write_response(
+                        &log,
+                        &server::Response {
+                            ret: server::ErrorCode::Ok as i32,
+                            msg: "version",
+                            obj: json!({
+                                "version":      VERSION,
+                                "targets":      ["myx", "con", "all", "cfg"],
+                                "capabilities": CAPABILITIES,
+                            }),
+                        },
+                        &mut stream,
+                    );
+                }
+                DaemonCmd::GetState => {
+                    // Gathered under the locks, `cli` first, and sent once
+                    // they are released
+                    let failures = jacon::failures_info(&failures);
+                    let state = {
+                        let jcli = cli.lock().unwrap();
+                        let jcli = jcli.as_inactive().ok();
+                        let jack = jcli.map(|cli| {
+                            json!({
+                                "client_name": cli.name(),
+                                "sample_rate": cli.sample_rate(),
+                                "buffer_size": cli.buffer_size(),
+                                "cpu_load":    cli.cpu_load(),
+                            })
+                        });
+
+                        let cfg = cfg.read().unwrap();
+                        let mixer = &cfg.mixer;
+                        json!({
+                            "version":  VERSION,
+                            "config":   cfg.path,
+                            "mixer":    {
+                                "channels": mixer.get_channels_info(),
+                                "monitor":  {
+                                    "port":  mixer.monitor.channel,
+                                    "ptype": if mixer.monitor.is_input {"in"} else {"out"},
+                                },
+                            },
+                            "patchbay": jacon::patchbay_state(jcli, &cfg, failures, &log),
+                            // null while not connected to a JACK server
+                            "jack":     jack,
+                        })
+                    };
+                    server::write_response(
+                        &log,
+                        &server::Response {
+                            ret: server::ErrorCode::Ok as i32,
+                            msg: "state",
+                            obj: state,
+                        },
+                        &mut stream,
+                    );
+                }
+                DaemonCmd::Save | DaemonCmd::Load => {
+                    if let Err(e) = t_myx.send((stream, command)) {
+                        let (mut stream, _) = e.0;
+                        server::write_response(
+                            &log,
+                            &server::Response::error(server::ErrorCode::Internal, "Mixer is gone!"),
+                            &mut stream,
+                        );
+                    }
+                }
+                DaemonCmd::Shutdown => {
+                    info!(log, "Shutdown requested by {:?}", command.id);
+                    server::write_response(
+                        &log,
+                        &server::Response {
+                            ret: server::ErrorCode::Ok as i32,
+                            msg: "shutting down",
+                            obj: json!({}),
+                        },
+                        &mut stream,
+                    );
+                    let _ = t_quit.send(()).log_err(&log);
+                }
+            }
+        }));
+    }
+
+    pub fn get_cmd_sender(&self) -> Option<&Sender<(server::Stream, server::Command)>> {
+        return self.t_cmd.as_ref();
+    }
+}
@@ -0,0 +1,213 @@
+extern crate slog;
+
+use std;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+use serde_json::Value;
+
+use server;
+
+/// Events a subscriber can fall behind by before it gets dropped
+const QUEUE_LEN: usize = 256;
+
+/// Which events a subscription receives; `None` matches anything
+#[derive(Debug)]
+pub struct Filter {
+    /// `volume`, `balance`, `connections`, `mute`, `solo`, `send`, `monitor`,
+    /// `ports` or `patchbay`
+    pub property: Option<String>,
+    pub is_output: Option<bool>,
+    pub name: Option<String>,
+}
+
+impl Filter {
+    /// Filter from the options of a `subscribe` command: `[PROPERTY, TYPE,
+    /// NAME]`, where any of them can be left out or given as `*`
+    pub fn from_opts(opts: &[String]) -> Result<Self, ()> {
+        let opt = |i: usize| {
+            opts.get(i)
+                .map(|o| o.as_str())
+                .and_then(|o| if o == "*" || o == "all" { None } else { Some(o) })
+        };
+
+        let property = match opt(0) {
+            None => None,
+            Some(p) => Some(
+                match p {
+                    "volume" | "vol" | "v" => "volume",
+                    "balance" | "bal" | "b" => "balance",
+                    "connections" | "cons" | "con" | "c" => "connections",
+                    "mute" => "mute",
+                    "solo" => "solo",
+                    "send" => "send",
+                    "monitor" | "mon" | "m" => "monitor",
+                    "ports" | "channels" => "ports",
+                    "patchbay" | "jack" => "patchbay",
+                    _ => return Err(()),
+                }.to_owned(),
+            ),
+        };
+        let is_output = match opt(1) {
+            None => None,
+            Some("input") | Some("in") | Some("i") => Some(false),
+            Some("output") | Some("out") | Some("o") => Some(true),
+            Some(_) => return Err(()),
+        };
+
+        Ok(Filter {
+            property,
+            is_output,
+            name: opt(2).map(|n| n.to_owned()),
+        })
+    }
+
+    fn matches_port(&self, port: &(bool, String)) -> bool {
+        self.is_output.map_or(true, |o| o == port.0)
+            && self.name.as_ref().map_or(true, |n| n == &port.1)
+    }
+
+    fn matches(&self, ev: &Event) -> bool {
+        self.property.as_ref().map_or(true, |p| p == ev.property)
+            && (ev.ports.is_empty() || ev.ports.iter().any(|p| self.matches_port(p)))
+    }
+}
+
+/// Something that happened to the mixer or the patchbay
+pub struct Event<'a> {
+    pub property: &'a str,
+    /// The ports concerned, as `(is_output, name)`
+    pub ports: Vec<(bool, String)>,
+    pub msg: &'a str,
+    pub obj: Value,
+    /// The ports no longer exist under their name, so one-shot monitors of
+    /// any of their properties get this event instead and are done
+    pub closes: bool,
+}
+
+/// A client waiting for events. Its replies go through a queue to a thread
+/// of its own, so that a client that stops reading never blocks `emit`
+#[derive(Debug)]
+struct Subscriber {
+    id: usize,
+    /// Connection the subscription was made on, the only one that can
+    /// cancel it
+    conn: usize,
+    queue: SyncSender<(String, Value)>,
+    filter: Filter,
+    once: bool,
+    log: slog::Logger,
+}
+
+/// The clients waiting for events: either one-shot monitors (`mon`), that
+/// get the object of the first matching event as a plain reply, or
+/// subscriptions, that get every matching event until they unsubscribe or
+/// go away
+#[derive(Debug)]
+pub struct Events {
+    next_id: AtomicUsize,
+    subs: Mutex<Vec<Subscriber>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Events {
+            next_id: AtomicUsize::new(1),
+            subs: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(
+        &self,
+        stream: server::Stream,
+        filter: Filter,
+        once: bool,
+        log: slog::Logger,
+    ) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (queue, r_queue) = sync_channel::<(String, Value)>(QUEUE_LEN);
+        let conn = stream.conn();
+        let mut stream = stream;
+        let wlog = log.clone();
+        thread::spawn(move || {
+            // Ends once the subscriber is dropped and its queue drained
+            for (msg, obj) in r_queue {
+                let res = server::try_write_response(
+                    &wlog,
+                    &server::Response {
+                        ret: server::ErrorCode::Ok as i32,
+                        msg: &msg,
+                        obj,
+                    },
+                    &mut stream,
+                );
+                if let Err(e) = res {
+                    info!(wlog, "Dropping subscription {}: {}", id, e);
+                    break;
+                }
+            }
+        });
+
+        self.subs.lock().unwrap().push(Subscriber {
+            id,
+            conn,
+            queue,
+            filter,
+            once,
+            log,
+        });
+        id
+    }
+
+    /// Cancel a subscription, if it was made on the connection `conn`
+    pub fn unsubscribe(&self, id: usize, conn: usize) -> Result<(), ()> {
+        let mut subs = self.subs.lock().unwrap();
+        let len = subs.len();
+        subs.retain(|s| s.id != id || s.conn != conn);
+        if subs.len() < len {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Queue an event for everyone interested, dropping the one-shot monitors
+    /// it was sent to, the clients that can't be written to anymore and the
+    /// ones too far behind
+    pub fn emit(&self, ev: &Event) {
+        let mut subs = self.subs.lock().unwrap();
+        for sub in std::mem::replace(&mut *subs, Vec::new()) {
+            let released = sub.once
+                && ev.closes
+                && ev.ports.iter().any(|p| sub.filter.matches_port(p));
+            if !sub.filter.matches(ev) && !released {
+                subs.push(sub);
+                continue;
+            }
+
+            let obj = if sub.once {
+                ev.obj.clone()
+            } else {
+                json!({
+                    "sub":   sub.id,
+                    "event": ev.property,
+                    "data":  ev.obj,
+                })
+            };
+            match sub.queue.try_send((ev.msg.to_owned(), obj)) {
+                Ok(_) => {
+                    if !sub.once {
+                        subs.push(sub);
+                    }
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!(sub.log, "Dropping subscription {}: client is not reading", sub.id)
+                }
+                // The writer already logged why
+                Err(TrySendError::Disconnected(_)) => (),
+            }
+        }
+    }
+}
@@ -3,12 +3,13 @@ extern crate jam;
 extern crate slog;
 
 use std;
-use std::net::TcpStream;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::mpsc::{
     channel,
     Receiver,
     Sender,
     RecvError,
+    RecvTimeoutError,
     SendError,
 };
 use std::sync::{
@@ -18,14 +19,18 @@ use std::sync::{
     PoisonError,
 };
 use std::thread;
-
-use serde_json;
+use std::time::{Duration, Instant};
 
 use jack as j;
-use jam::JackClientUtils;
+use jam::{JackClientUtils, JackPortUtils};
+use serde_json::Value;
 
+use command::{CaptureTo, PatchbayCmd};
 use config;
+use events;
+use patterns;
 use server;
+use server::ErrorCode;
 
 use utils::Connections;
 use utils::LogError;
@@ -36,7 +41,6 @@ pub enum Error {
     SendError,
     JackError(j::Error),
     PoisonError,
-    PortNotFound,
     JaconUninitialized,
 }
 
@@ -60,10 +64,23 @@ pub enum Signals {
     DisconnectAll,
     ReconnectGood,
 
-    RetryIn(u64, Box<Signals>),
     TryConnection(bool, String, String),
+    /// A failed (dis)connection is due for another attempt
+    Retry(bool, String, String),
 
+    /// A port came, so connect it and the other ports of its client the way
+    /// the rules want
     ReconnectPort(String),
+    /// A client a client rule might apply to came or went, or changed its
+    /// ports
+    ReconnectClient(String),
+    /// A rule was added (or removed), so (dis)connect the ports it matches
+    ApplyRule(bool, String, String),
+    /// The enforcement policies changed, so bring every connection in line
+    Enforce,
+    /// Ports came or went, so switch the fallback groups to the best route
+    /// that is available now
+    SwitchFallbacks,
     // Connect(String, String, bool),
 }
 
@@ -76,39 +93,499 @@ macro_rules! as_inactive {
     }
 }
 
-trait RetryConnection {
-    fn retry_if_fail_in(&self, u64, Signals, &Sender<Signals>)
-        -> Result<(), Error>;
+type AM<T> = Arc<Mutex<T>>;
+/// Whoever needs both locks takes this one before the config
+type AMAnyClient = AM<jam::AnyClient>;
+
+/// A (dis)connection JACK refused, waiting to be retried or given up on
+pub struct Failure {
+    connecting: bool,
+    attempts: u32,
+    error: String,
+    /// When the next attempt is due, `None` once given up
+    retry_at: Option<Instant>,
 }
 
-impl RetryConnection for Result<(), j::Error> {
-    fn retry_if_fail_in(&self, delay: u64, sig: Signals, t_sig: &Sender<Signals>) 
-        -> Result<(), Error> {
+pub type Failures = AM<HashMap<(String, String), Failure>>;
 
-        if let &Err(ref e) = self {
-            match e {
-                &j::Error::PortConnectionError(_, _) => {
-                    t_sig.send(Signals::RetryIn(delay, Box::new(sig)))?;
-                }
-                &j::Error::PortDisconnectionError => {
-                    // t_sig.send(Signals::RetryIn(delay, Box::new(sig)));
+/// Sends signals back to the signal loop once they are due, all from a single
+/// thread
+struct Timer {
+    t_timer: Sender<(Instant, Signals)>,
+}
+
+impl Timer {
+    fn start(t_sig: Sender<Signals>) -> Self {
+        let (t_timer, r_timer) = channel();
+        thread::spawn(move || {
+            let mut due: BTreeMap<(Instant, u64), Signals> = BTreeMap::new();
+            let mut seq = 0;
+            loop {
+                let received = match due.keys().next().cloned() {
+                    Some(key) => {
+                        let now = Instant::now();
+                        if key.0 <= now {
+                            let sig = due.remove(&key).unwrap();
+                            if t_sig.send(sig).is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                        r_timer.recv_timeout(key.0 - now)
+                    }
+                    None => r_timer.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok((at, sig)) => {
+                        due.insert((at, seq), sig);
+                        seq += 1;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
                 }
-                _ => (),
-            };
-        }
+            }
+        });
+        Timer { t_timer }
+    }
+
+    fn schedule(&self, delay: Duration, sig: Signals) -> Result<(), Error> {
+        self.t_timer.send((Instant::now() + delay, sig))?;
         Ok(())
     }
 }
 
-type AM<T> = Arc<Mutex<T>>;
-type AMAnyClient = AM<jam::AnyClient>;
+/// JACK output and input port names (none while not connected to JACK),
+/// optionally only those of a type matching `ptype`
+fn jack_ports_of(cli: Option<&j::Client>, ptype: Option<&str>) -> (Vec<String>, Vec<String>) {
+    match cli {
+        Some(cli) => (
+            cli.ports(None, ptype, j::PortFlags::IS_OUTPUT),
+            cli.ports(None, ptype, j::PortFlags::IS_INPUT),
+        ),
+        None => (Vec::new(), Vec::new()),
+    }
+}
+
+fn jack_ports(cli: Option<&j::Client>) -> (Vec<String>, Vec<String>) {
+    jack_ports_of(cli, None)
+}
+
+/// The `(output, input)` port pairs the client rules connect among the
+/// JACK audio ports
+fn client_wanted_pairs(
+    cli: Option<&j::Client>,
+    clients: &HashMap<String, HashSet<String>>,
+    log: &slog::Logger,
+) -> Vec<(String, String)> {
+    if clients.is_empty() {
+        return Vec::new();
+    }
+    let (outputs, inputs) = jack_ports_of(cli, Some("audio"));
+
+    let mut pairs = Vec::new();
+    for (src, dsts) in clients {
+        match patterns::client_pairs(src, dsts, &outputs, &inputs) {
+            Ok(p) => pairs.extend(p),
+            Err(e) => warn!(log, "Skipping client rule for `{}`: {}", src, e),
+        }
+    }
+    pairs
+}
+
+/// Whether a fallback route has port pairs, all with an existing input port
+fn route_available(pairs: &[(String, String)], inputs: &[String]) -> bool {
+    !pairs.is_empty() && pairs.iter().all(|&(_, ref i)| inputs.contains(i))
+}
+
+/// The `(output, input)` port pairs of every route of each fallback group,
+/// along with the route in use: the first one with pairs whose input ports
+/// all exist
+fn fallback_routes(
+    cli: Option<&j::Client>,
+    fallbacks: &[Vec<HashMap<String, HashSet<String>>>],
+    log: &slog::Logger,
+) -> Vec<(Option<usize>, Vec<Vec<(String, String)>>)> {
+    let (outputs, inputs) = jack_ports(cli);
+
+    fallbacks
+        .iter()
+        .map(|group| {
+            let routes: Vec<Vec<(String, String)>> = group
+                .iter()
+                .map(|route| {
+                    let mut pairs = Vec::new();
+                    for (oo, iis) in route {
+                        match patterns::rule_pairs(oo, iis, &outputs, &inputs) {
+                            Ok(p) => pairs.extend(p),
+                            Err(e) => warn!(log, "Skipping fallback rule for `{}`: {}", oo, e),
+                        }
+                    }
+                    pairs
+                }).collect();
+            let active = routes.iter().position(|pairs| route_available(pairs, &inputs));
+            (active, routes)
+        }).collect()
+}
+
+/// The `(output, input)` port pairs of the fallback routes in use
+fn fallback_pairs(
+    cli: Option<&j::Client>,
+    fallbacks: &[Vec<HashMap<String, HashSet<String>>>],
+    log: &slog::Logger,
+) -> Vec<(String, String)> {
+    fallback_routes(cli, fallbacks, log)
+        .into_iter()
+        .filter_map(|(active, mut routes)| active.map(|k| routes.swap_remove(k)))
+        .flat_map(|pairs| pairs)
+        .collect()
+}
+
+/// The `(output, input)` port pairs all the rules connect among the JACK
+/// ports, skipping (and logging) rules that aren't valid patterns
+fn wanted_pairs(
+    cli: Option<&j::Client>,
+    cfg: &config::Config,
+    log: &slog::Logger,
+) -> Vec<(String, String)> {
+    let (outputs, inputs) = jack_ports(cli);
+
+    let mut pairs = Vec::new();
+    for (oo, iis) in &cfg.connections {
+        match patterns::rule_pairs(oo, iis, &outputs, &inputs) {
+            Ok(p) => pairs.extend(p),
+            Err(e) => warn!(log, "Skipping rule for `{}`: {}", oo, e),
+        }
+    }
+    pairs.extend(client_wanted_pairs(cli, &cfg.clients, log));
+    pairs.extend(fallback_pairs(cli, &cfg.fallbacks, log));
+    pairs
+}
+
+/// The JACK ports by direction, with their connections and whether the
+/// rules want them
+fn graph_info(cli: &j::Client, cfg: &config::Config, log: &slog::Logger) -> Value {
+    let wanted = wanted_pairs(Some(cli), cfg, log);
+    let graph = |flags| -> Vec<Value> {
+        cli.ports(None, None, flags)
+            .iter()
+            .filter_map(|p| port_info(Some(cli), &wanted, p))
+            .collect()
+    };
+    json!({
+        "outputs": graph(j::PortFlags::IS_OUTPUT),
+        "inputs":  graph(j::PortFlags::IS_INPUT),
+    })
+}
+
+/// The (dis)connections JACK refused, with when they are tried again
+pub fn failures_info(failures: &Failures) -> Vec<Value> {
+    let now = Instant::now();
+    failures
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&(ref oname, ref iname), failure)| {
+            let retry_in = failure.retry_at.map(|at| {
+                let left = if at > now { at - now } else { Duration::from_millis(0) };
+                left.as_secs() * 1000 + u64::from(left.subsec_millis())
+            });
+            json!({
+                "output_name": oname,
+                "input_name":  iname,
+                "connecting":  failure.connecting,
+                "attempts":    failure.attempts,
+                "error":       failure.error,
+                "retrying":    failure.retry_at.is_some(),
+                "retry_in_ms": retry_in,
+            })
+        }).collect()
+}
+
+/// Everything about the patchbay: its part of the config, the JACK graph
+/// (null while not connected) and the failures from `failures_info`
+pub fn patchbay_state(
+    cli: Option<&j::Client>,
+    cfg: &config::Config,
+    failures: Vec<Value>,
+    log: &slog::Logger,
+) -> Value {
+    json!({
+        "connections": cfg.connections,
+        "clients":     cfg.clients,
+        "forbidden":   cfg.forbidden,
+        "fallbacks":   cfg.fallbacks,
+        "retry":       cfg.retry,
+        "enforcement": cfg.enforcement,
+        "graph":       cli.map(|cli| graph_info(cli, cfg, log)),
+        "failures":    failures,
+    })
+}
+
+/// Add rules, captured or from a profile, to the rules in use and connect
+/// what they want
+fn merge_rules(
+    cfg: &RwLock<config::Config>,
+    rules: &HashMap<String, HashSet<String>>,
+    t_sig: &Sender<Signals>,
+    log: &slog::Logger,
+) {
+    {
+        let mut cfg = cfg.write().unwrap();
+        for (oname, inames) in rules {
+            for iname in inames {
+                cfg.connections.connect(true, oname, iname);
+            }
+        }
+        cfg.compile_rules();
+    }
+    // Patterns may match more than what is connected
+    let _ = t_sig.send(Signals::ReconnectGood).log_err(log);
+}
+
+/// Whether the connection of two ports is forbidden by a rule
+fn forbidden(cfg: &config::Config, oname: &str, iname: &str) -> bool {
+    cfg.forbids(oname, iname)
+}
+
+/// Whether the connection of two ports has to be undone under their
+/// enforcement policy, given whether the rules want it
+fn must_disconnect(cfg: &config::Config, wanted: bool, oname: &str, iname: &str) -> bool {
+    match cfg.enforcement.policy(oname, iname) {
+        config::Enforcement::Strict => !wanted || forbidden(cfg, oname, iname),
+        config::Enforcement::Additive => forbidden(cfg, oname, iname),
+        config::Enforcement::Ignore => false,
+    }
+}
+
+/// Schedule disconnecting a port from every port it is connected to that
+/// isn't in `wanted` under a strict policy, or against a forbidden rule,
+/// leaving the other connections alone. Failures end up with the retries
+/// like any other
+fn disconnect_strict(
+    cli: &j::Client,
+    cfg: &config::Config,
+    wanted: &HashSet<(String, String)>,
+    port_name: &str,
+    t_sig: &Sender<Signals>,
+    log: &slog::Logger,
+) -> Result<(), Error> {
+    let port = match cli.port_by_name(port_name) {
+        Some(port) => port,
+        None => {
+            warn!(log, "Port `{}` does not exist", port_name);
+            return Ok(());
+        }
+    };
+
+    let is_input = port.flags().contains(j::PortFlags::IS_INPUT);
+    for other in cli.port_connections(port_name).unwrap_or_default() {
+        let pair = if is_input { (other, port_name.to_owned()) } else { (port_name.to_owned(), other) };
+        if must_disconnect(cfg, wanted.contains(&pair), &pair.0, &pair.1) {
+            t_sig.send(Signals::TryConnection(false, pair.0, pair.1))?;
+        }
+    }
+    Ok(())
+}
+
+/// Bring the connections of a port in line with the rules: undo the ones
+/// that have to go and make the wanted ones that are missing, leaving the
+/// wanted ones in place alone
+fn reconnect_port(
+    cli: &j::Client,
+    cfg: &config::Config,
+    wanted: &HashSet<(String, String)>,
+    port_name: &str,
+    t_sig: &Sender<Signals>,
+    log: &slog::Logger,
+) -> Result<(), Error> {
+    let port = match cli.port_by_name(port_name) {
+        Some(port) => port,
+        None => {
+            warn!(log, "Port `{}` does not exist", port_name);
+            return Ok(());
+        }
+    };
+
+    let is_input = port.flags().contains(j::PortFlags::IS_INPUT);
+    disconnect_strict(cli, cfg, wanted, port_name, t_sig, log)?;
+    let connected: HashSet<String> = cli.port_connections(port_name).unwrap_or_default().into_iter().collect();
+    for &(ref oo, ref ii) in wanted {
+        let (this, other) = if is_input { (ii, oo) } else { (oo, ii) };
+        if this == port_name && !connected.contains(other) {
+            t_sig.send(Signals::TryConnection(true, oo.clone(), ii.clone()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reconnect the outputs of a client that a client rule applies to, as the
+/// way they are paired depends on how many of them there are. `skip` is a
+/// port that was just taken care of
+fn reconnect_client(
+    cli: &j::Client,
+    cfg: &config::Config,
+    wanted: &HashSet<(String, String)>,
+    client: &str,
+    skip: Option<&str>,
+    t_sig: &Sender<Signals>,
+    log: &slog::Logger,
+) -> Result<(), Error> {
+    let ruled = cfg.clients.keys().any(|src| {
+        patterns::Pattern::parse(src)
+            .map(|p| p.is_match(client))
+            .unwrap_or(false)
+    });
+    if !ruled {
+        return Ok(());
+    }
+
+    info!(log, "Reevaluating connections for client: `{}`", client);
+    let prefix = format!("{}:", client);
+    for oname in cli.ports(None, None, j::PortFlags::IS_OUTPUT) {
+        if oname.starts_with(&prefix) && skip != Some(oname.as_str()) {
+            reconnect_port(cli, cfg, wanted, &oname, t_sig, log)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the JACK output port `oname` is connected to the input port `iname`
+fn jack_connected(cli: Option<&j::Client>, oname: &str, iname: &str) -> bool {
+    cli.and_then(|c| c.port_by_name(oname))
+        .map_or(false, |p| p.is_connected_to(iname).unwrap_or(false))
+}
+
+/// Rules in the form of `Config::connections` for the connections in the JACK
+/// graph, only those of the clients matching one of `clients` if any, with
+/// PIDs in client names turned into wildcards if `generalize`
+pub fn capture_graph(
+    cli: &j::Client,
+    clients: &[patterns::Pattern],
+    generalize: bool,
+) -> HashMap<String, HashSet<String>> {
+    let wanted = |port: &str| {
+        let client = port.split(':').next().unwrap_or("");
+        clients.is_empty() || clients.iter().any(|c| c.is_match(client))
+    };
+    let name = |port: &str| {
+        if generalize {
+            patterns::generalize(port)
+        } else {
+            port.to_owned()
+        }
+    };
+
+    let mut rules = HashMap::new();
+    for oname in cli.ports(None, None, j::PortFlags::IS_OUTPUT) {
+        for iname in cli.port_connections(&oname).unwrap_or_default() {
+            if wanted(&oname) || wanted(&iname) {
+                rules.connect(true, &name(&oname), &name(&iname));
+            }
+        }
+    }
+    rules
+}
+
+/// Rule object of a port, client or forbidden rule from the `(output, input)`
+/// port pairs it connects, along with whether it is in place
+fn rule_info(
+    cli: Option<&j::Client>,
+    pairs: &[(String, String)],
+    oo: &str,
+    ii: &str,
+    client: bool,
+    forbidden: bool,
+) -> (Value, bool) {
+    let matches: Vec<Value> = pairs
+        .iter()
+        .map(|&(ref oname, ref iname)| {
+            json!({
+                "output_name":   oname,
+                "input_name":    iname,
+                "output_exists": cli.map_or(false, |c| c.port_by_name(oname).is_some()),
+                "input_exists":  cli.map_or(false, |c| c.port_by_name(iname).is_some()),
+                "connected":     jack_connected(cli, oname, iname),
+            })
+        }).collect();
+    // A forbidden rule is in place when none of its ports are connected
+    let connected = if forbidden {
+        pairs.iter().any(|&(ref oname, ref iname)| jack_connected(cli, oname, iname))
+    } else {
+        !pairs.is_empty() && pairs.iter().all(|&(ref oname, ref iname)| jack_connected(cli, oname, iname))
+    };
+
+    let rule = json!({
+        "output_name": oo,
+        "input_name":  ii,
+        "client":      client,
+        "forbidden":   forbidden,
+        "pattern":     patterns::Pattern::is_pattern(oo) || patterns::Pattern::is_pattern(ii),
+        "connected":   connected,
+        "matches":     matches,
+    });
+    (rule, connected != forbidden)
+}
+
+/// Port object of a JACK port, or of a port that only appears in the rules
+/// while it doesn't exist
+fn port_info(cli: Option<&j::Client>, wanted: &[(String, String)], name: &str) -> Option<Value> {
+    let port = cli.and_then(|c| c.port_by_name(name));
+    let as_output: Vec<&String> = wanted
+        .iter()
+        .filter(|&&(ref oo, _)| oo == name)
+        .map(|&(_, ref ii)| ii)
+        .collect();
+    let as_input: Vec<&String> = wanted
+        .iter()
+        .filter(|&&(_, ref ii)| ii == name)
+        .map(|&(ref oo, _)| oo)
+        .collect();
+
+    let is_output = match port {
+        Some(ref p) => !p.flags().contains(j::PortFlags::IS_INPUT),
+        None if !as_output.is_empty() => true,
+        None if !as_input.is_empty() => false,
+        None => return None,
+    };
+    let mut rules = if is_output { as_output } else { as_input };
+    rules.sort();
+    rules.dedup();
+
+    let latency = |mode| {
+        port.as_ref().map(|p| {
+            let (min, max) = p.latency_range(mode);
+            json!([min, max])
+        })
+    };
+    let flag = |flag| port.as_ref().map(|p| p.flags().contains(flag));
+
+    Some(json!({
+        "port":     name,
+        "ptype":    if is_output {"out"} else {"in"},
+        "client":   name.split(':').next(),
+        "exists":   port.is_some(),
+        "type":     port.as_ref().and_then(|p| p.port_type().ok()).map(|t| {
+            if t.contains("midi") { "midi" } else if t.contains("audio") { "audio" } else { t }
+        }),
+        "aliases":  port.as_ref().and_then(|p| p.aliases().ok()).unwrap_or_default(),
+        "physical": flag(j::PortFlags::IS_PHYSICAL),
+        "terminal": flag(j::PortFlags::IS_TERMINAL),
+        "latency":  {
+            "capture":  latency(j::LatencyType::Capture),
+            "playback": latency(j::LatencyType::Playback),
+        },
+        "cons":     cli.and_then(|c| c.port_connections(name)).unwrap_or_default(),
+        "rules":    rules,
+    }))
+}
 
 pub struct ConnectionKit {
     log: slog::Logger,
     cli: AMAnyClient,
     cfg: Arc<RwLock<config::Config>>,
     t_sig: Option<Sender<Signals>>,
-    t_cmd: Option<Sender<(TcpStream, server::Command)>>,
+    t_cmd: Option<Sender<(server::Stream, server::Command)>>,
+    failures: Failures,
     sig_thread: Option<std::thread::JoinHandle<()>>,
     cmd_thread: Option<std::thread::JoinHandle<()>>,
     // monitors: AM<Vec<(TcpStream, String, String)>>
@@ -122,12 +599,18 @@ impl ConnectionKit {
             cfg,
             t_sig: None,
             t_cmd: None,
+            failures: Arc::new(Mutex::new(HashMap::new())),
             sig_thread: None,
             cmd_thread: None,
             // monitors: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    /// The (dis)connections JACK refused, for `get state`
+    pub fn get_failures(&self) -> Failures {
+        self.failures.clone()
+    }
+
     pub fn init(&mut self, jclient: &mut jam::Client) -> Result<(), Error> {
         let (_t_sig, r_sig) = channel();
         self.t_sig = Some(_t_sig.clone());
@@ -137,9 +620,10 @@ impl ConnectionKit {
         let log = self.log.clone();
         let cli = self.cli.clone();
         let cfg = self.cfg.clone();
+        let failures = self.failures.clone();
         // Start signal loop
         self.sig_thread = Some(thread::spawn(move || {
-            Self::sig_loop((t_sig, r_sig), log, cli, cfg)
+            Self::sig_loop((t_sig, r_sig), log, cli, cfg, failures)
                 .expect("jacon signal loop failed");
         }));
 
@@ -173,11 +657,31 @@ impl ConnectionKit {
                 if of { "" } else { "un" },
                 pname
             );
+            // A new port also takes care of its client
+            let sig = if of {
+                Signals::ReconnectPort(pname)
+            } else {
+                Signals::ReconnectClient(pname.split(':').next().unwrap_or("").to_owned())
+            };
+            t_sig
+                .send(sig)
+                .expect("seding reconnection signal in jack callback");
+            t_sig
+                .send(Signals::SwitchFallbacks)
+                .expect("seding reconnection signal in jack callback");
+        })));
+
+        // Hook client_registration
+        let t_sig = _t_sig.clone();
+        jclient.hook(jam::CB::client_registration(Box::new(move |_, cn, of| {
             if of {
                 t_sig
-                    .send(Signals::ReconnectPort(pname))
+                    .send(Signals::ReconnectClient(cn.to_owned()))
                     .expect("seding reconnection signal in jack callback");
             }
+            t_sig
+                .send(Signals::SwitchFallbacks)
+                .expect("seding reconnection signal in jack callback");
         })));
 
         // Hook client_reconnection
@@ -187,6 +691,7 @@ impl ConnectionKit {
 
             t_sig.send(Signals::DisconnectAll).expect(msg);
             t_sig.send(Signals::ReconnectGood).expect(msg);
+            t_sig.send(Signals::SwitchFallbacks).expect(msg);
         })));
 
         Ok(())
@@ -197,8 +702,10 @@ impl ConnectionKit {
         log: slog::Logger,
         cli: AMAnyClient,
         config: Arc<RwLock<config::Config>>,
+        failures: Failures,
     ) -> Result<(), Error> {
         let (t_sig, r_sig) = sigs;
+        let timer = Timer::start(t_sig.clone());
         let mut disable_check_connections = false;
         loop {
             let sig = r_sig.recv()?;
@@ -206,40 +713,38 @@ impl ConnectionKit {
                 Signals::ReconnectPort(port_name) => {
                     info!(log, "Reevaluating connections for port: `{}`", port_name);
                     as_inactive!(cli, log, {
-                        let port = cli
-                            .port_by_name(&port_name)
-                            .ok_or(Error::PortNotFound)?;
-
-                        let is_input = port.flags().contains(j::PortFlags::IS_INPUT);
-                        cli.disconnect(&port)?;
-                        for (oo, iis) in &config.read()?.connections {
-                            for ii in iis {
-
-                                if  (is_input  && ii == &port_name) || 
-                                    (!is_input && oo == &port_name) {
-
-                                    t_sig
-                                        .send(Signals::TryConnection(
-                                                true, 
-                                                oo.clone(),
-                                                ii.clone())
-                                            )?;
-                                }
-                            }
-                        }
+                        let config = config.read()?;
+                        let wanted: HashSet<(String, String)> =
+                            wanted_pairs(Some(cli), &config, &log).into_iter().collect();
+                        reconnect_port(cli, &config, &wanted, &port_name, &t_sig, &log)?;
+                        // A new port can change how a client rule pairs the
+                        // other ones
+                        let client = port_name.split(':').next().unwrap_or("");
+                        reconnect_client(cli, &config, &wanted, client, Some(&port_name), &t_sig, &log)?;
                     });
                 }
-                Signals::RetryIn(delay, sig) => {
-                    warn!(log, "{:?} failed and got rescheduled", *sig);
-                    debug!(log, "scheduling retry in {} milliseconds...", delay);
-                    let t_sig = t_sig.clone();
-                    thread::spawn(move || {
-                        thread::sleep(std::time::Duration::from_millis(delay));
-                        t_sig
-                            .send(*sig)
-                            .expect("sending signal from callback to jacon");
+                Signals::ReconnectClient(client) => {
+                    as_inactive!(cli, log, {
+                        let config = config.read()?;
+                        let wanted: HashSet<(String, String)> =
+                            wanted_pairs(Some(cli), &config, &log).into_iter().collect();
+                        reconnect_client(cli, &config, &wanted, &client, None, &t_sig, &log)?;
                     });
                 }
+                Signals::Retry(of, oname, iname) => {
+                    // The failure may have been resolved, turned around or
+                    // rescheduled in the meantime
+                    let due = match failures.lock()?.get(&(oname.clone(), iname.clone())) {
+                        Some(failure) => {
+                            failure.connecting == of
+                                && failure.retry_at.map_or(false, |at| at <= Instant::now())
+                        }
+                        None => false,
+                    };
+                    if due {
+                        t_sig.send(Signals::TryConnection(of, oname, iname))?;
+                    }
+                }
                 Signals::TryConnection(of, oname, iname) => {
                     debug!(
                         log,
@@ -249,27 +754,78 @@ impl ConnectionKit {
                         iname
                     );
                     as_inactive!(cli, log, {
-                        if cli.ports(Some(&iname), None, j::PortFlags::IS_INPUT).len() == 1
+                        let pair = (oname.clone(), iname.clone());
+                        let (policy, forbidden) = {
+                            let config = config.read()?;
+                            (config.enforcement.policy(&oname, &iname), forbidden(&config, &oname, &iname))
+                        };
+                        if policy == config::Enforcement::Ignore {
+                            debug!(log, "Ports are not managed");
+                            failures.lock()?.remove(&pair);
+                        } else if of && forbidden {
+                            info!(log, "Not connecting `{}` and `{}`: forbidden", oname, iname);
+                            failures.lock()?.remove(&pair);
+                        } else if jack_connected(Some(cli), &oname, &iname) == of {
+                            debug!(log, "Already {}connected", if of { "" } else { "dis" });
+                            failures.lock()?.remove(&pair);
+                        } else if cli.ports(Some(&iname), None, j::PortFlags::IS_INPUT).len() == 1
                             && cli
                                 .ports(Some(&oname), None, j::PortFlags::IS_OUTPUT)
                                 .len()
                                 == 1
                         {
-                            cli.connect_ports_by_name_if(&of, &oname, &iname)
-                                .log_err(&log)
-                                .map_err(|e| {
-                                    warn!(log, "FAILED! Rescheduling!");
-                                    e
-                                }).retry_if_fail_in(
-                                    100,
-                                    Signals::TryConnection(of, oname, iname),
-                                    &t_sig,
-                                )?;
+                            match cli.connect_ports_by_name_if(&of, &oname, &iname) {
+                                Ok(()) => {
+                                    failures.lock()?.remove(&pair);
+                                }
+                                Err(e) => {
+                                    let mut failures = failures.lock()?;
+                                    let failure = failures.entry(pair).or_insert(Failure {
+                                        connecting: of,
+                                        attempts: 0,
+                                        error: String::new(),
+                                        retry_at: None,
+                                    });
+                                    if failure.connecting != of {
+                                        failure.connecting = of;
+                                        failure.attempts = 0;
+                                    }
+                                    failure.attempts += 1;
+                                    failure.error = format!("{:?}", e);
+
+                                    let delay = config.read()?.retry.policy(&oname).delay(failure.attempts);
+                                    failure.retry_at = delay.map(|d| Instant::now() + d);
+                                    match delay {
+                                        Some(delay) => {
+                                            warn!(
+                                                log,
+                                                "{}connection of `{}` and `{}` failed ({}), retrying in {} ms",
+                                                if of { "" } else { "dis" },
+                                                oname,
+                                                iname,
+                                                failure.error,
+                                                delay.as_secs() * 1000 + u64::from(delay.subsec_millis())
+                                            );
+                                            timer.schedule(delay, Signals::Retry(of, oname, iname))?;
+                                        }
+                                        None => error!(
+                                            log,
+                                            "{}connection of `{}` and `{}` failed ({}), giving up after {} attempts",
+                                            if of { "" } else { "dis" },
+                                            oname,
+                                            iname,
+                                            failure.error,
+                                            failure.attempts
+                                        ),
+                                    }
+                                }
+                            }
                         } else {
                             warn!(
                                 log,
                                 "One or both of ports: `{}` and `{}` does not exist!", oname, iname
                             );
+                            failures.lock()?.remove(&pair);
                         }
                     });
                 }
@@ -285,13 +841,41 @@ impl ConnectionKit {
                     if disable_check_connections {
                         debug!(log, "Skipping connection checks");
                     } else {
-                        let mut is_fine = !connected;
-                        for (oo, iis) in &config.read()?.connections {
-                            if &oname == oo && iis.contains(&iname) {
-                                is_fine = connected;
-                                break;
-                            }
+                        let mut wanted = config.read()?.wants(&oname, &iname);
+                        if !wanted {
+                            as_inactive!(cli, log, {
+                                let pair = (oname.clone(), iname.clone());
+                                let config = config.read()?;
+                                wanted = client_wanted_pairs(Some(cli), &config.clients, &log).contains(&pair)
+                                    || fallback_pairs(Some(cli), &config.fallbacks, &log).contains(&pair);
+                            });
                         }
+                        let (is_fine, forbidden) = {
+                            let config = config.read()?;
+                            let forbidden = forbidden(&config, &oname, &iname);
+                            let is_fine = if connected {
+                                !must_disconnect(&config, wanted, &oname, &iname)
+                            } else {
+                                !wanted
+                                    || forbidden
+                                    || config.enforcement.policy(&oname, &iname) == config::Enforcement::Ignore
+                            };
+                            (is_fine, forbidden)
+                        };
+
+                        config.read()?.mixer.events.emit(&events::Event {
+                            property: "patchbay",
+                            ports: vec![(true, oname.clone()), (false, iname.clone())],
+                            msg: &format!("ports {}connected", if connected { "" } else { "dis" }),
+                            obj: json!({
+                                "output_name": &oname,
+                                "input_name":  &iname,
+                                "connected":   connected,
+                                "good":        is_fine,
+                                "forbidden":   forbidden,
+                            }),
+                            closes: false,
+                        });
 
                         let stat = if is_fine { "GOOD" } else { "BAD" };
                         let log = log.new(o!("stat" => stat));
@@ -325,27 +909,80 @@ impl ConnectionKit {
                 }
                 Signals::ReconnectGood => {
                     info!(log, "Reconnecting all good");
-                    for (oo, iis) in &config.read()?.connections {
-                        for ii in iis {
-                            t_sig
-                                .send(Signals::TryConnection(
-                                        true,
-                                        oo.clone(),
-                                        ii.clone()
-                                        ))?;
+                    as_inactive!(cli, log, {
+                        for (oo, ii) in wanted_pairs(Some(cli), &*config.read()?, &log) {
+                            t_sig.send(Signals::TryConnection(true, oo, ii))?;
                         }
-                    }
+                    });
+                }
+                Signals::ApplyRule(of, oo, ii) => {
+                    debug!(log, "Applying {} rule: `{}` to `{}`", if of { "new" } else { "removed" }, oo, ii);
+                    as_inactive!(cli, log, {
+                        let (outputs, inputs) = jack_ports(Some(cli));
+                        let iis = Some(ii.clone()).into_iter().collect();
+                        let config = config.read()?;
+                        if let Ok(pairs) = patterns::rule_pairs(&oo, &iis, &outputs, &inputs).log_err(&log) {
+                            for (oname, iname) in pairs {
+                                // Other rules can still want what this one did
+                                if of || !config.wants(&oname, &iname) {
+                                    t_sig.send(Signals::TryConnection(of, oname, iname))?;
+                                }
+                            }
+                        }
+                    });
+                }
+                Signals::Enforce => {
+                    info!(log, "Enforcing policies");
+                    as_inactive!(cli, log, {
+                        let config = config.read()?;
+                        let wanted: HashSet<(String, String)> =
+                            wanted_pairs(Some(cli), &config, &log).into_iter().collect();
+                        for oname in cli.ports(None, None, j::PortFlags::IS_OUTPUT) {
+                            for iname in cli.port_connections(&oname).unwrap_or_default() {
+                                let pair = (oname.clone(), iname);
+                                if must_disconnect(&config, wanted.contains(&pair), &pair.0, &pair.1) {
+                                    t_sig.send(Signals::TryConnection(false, pair.0, pair.1))?;
+                                }
+                            }
+                        }
+                        for (oname, iname) in wanted {
+                            t_sig.send(Signals::TryConnection(true, oname, iname))?;
+                        }
+                    });
+                }
+                Signals::SwitchFallbacks => {
+                    as_inactive!(cli, log, {
+                        let config = config.read()?;
+                        if !config.fallbacks.is_empty() {
+                            let wanted: HashSet<(String, String)> =
+                                wanted_pairs(Some(cli), &config, &log).into_iter().collect();
+                            for (group, (active, routes)) in
+                                fallback_routes(Some(cli), &config.fallbacks, &log).into_iter().enumerate()
+                            {
+                                debug!(log, "Fallback group {} uses route {:?}", group, active);
+                                for (k, pairs) in routes.into_iter().enumerate() {
+                                    for (oname, iname) in pairs {
+                                        if Some(k) == active {
+                                            t_sig.send(Signals::TryConnection(true, oname, iname))?;
+                                        } else if jack_connected(Some(cli), &oname, &iname)
+                                            && !wanted.contains(&(oname.clone(), iname.clone()))
+                                        {
+                                            t_sig.send(Signals::TryConnection(false, oname, iname))?;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    });
                 }
                 Signals::DisconnectAll => {
                     info!(log, "Disconnecting all");
+                    failures.lock()?.clear();
                     t_sig.send(Signals::SetConnectionCheck(false))?;
                     as_inactive!(cli, log, {
+                        let config = config.read()?;
                         for ii in cli.ports(None, None, j::PortFlags::IS_INPUT) {
-                            cli.disconnect(
-                                &cli
-                                .port_by_name(&ii)
-                                .ok_or(Error::PortNotFound)?
-                                )?;
+                            disconnect_strict(cli, &config, &HashSet::new(), &ii, &t_sig, &log)?;
                         }
                     });
                     t_sig.send(Signals::SetConnectionCheck(true))?;
@@ -362,49 +999,59 @@ impl ConnectionKit {
 
         t_sig.send(Signals::DisconnectAll)?;
         t_sig.send(Signals::ReconnectGood)?;
+        t_sig.send(Signals::SwitchFallbacks)?;
 
         let (_t_cmd, r_cmd) = channel();
         self.t_cmd = Some(_t_cmd.clone());
         // let t_cmd = _t_cmd.clone();
         let cfg = self.cfg.clone();
+        let cli = self.cli.clone();
         let log = self.log.clone();
+        let failures = self.failures.clone();
         self.cmd_thread = Some(thread::spawn(move || {
             loop {
-                let (mut stream, command): (TcpStream, server::Command) =
+                let (mut stream, command): (server::Stream, server::Command) =
                                             r_cmd.recv().unwrap();
 
-                match command.cmd.as_str() {
-                    "con" | "dis" | "tog" => {
-                        let iname = command.opts[0].clone();
-                        let oname = command.opts[1].clone();
-
-                        let connecting = match command.cmd.as_str() {
-                            "con" => true,
-                            "dis" => false,
-                            "tog" | _ => {
-                                !cfg.read().unwrap().connections.is_connected(&iname, &oname)
-                            }
-                        };
+                let command = match PatchbayCmd::parse(&command) {
+                    Ok(command) => command,
+                    Err((code, msg)) => {
+                        warn!(log, "{} ({} `{}` {:?})", msg, command.target, command.cmd, command.opts);
+                        server::write_response(&log, &server::Response::error(code, msg), &mut stream);
+                        continue;
+                    }
+                };
 
-                        // let msg = format!("{}connected `{}` and `{}`",
-                        //                   if connecting {""} else {"dis"}, iname, oname);
+                match command {
+                    PatchbayCmd::Connect(switch, oname, iname) => {
+                        if patterns::Pattern::parse(&oname).and(patterns::Pattern::parse(&iname)).is_err() {
+                            server::write_response(
+                                &log,
+                                &server::Response::error(ErrorCode::BadArgs, "Bad pattern!"),
+                                &mut stream,
+                            );
+                            continue;
+                        }
+                        let connecting =
+                            switch.apply(cfg.read().unwrap().connections.is_connected(&oname, &iname));
 
                         // Perform the (dis)connection
-                        cfg.write()
-                            .unwrap()
-                            .connections
-                            .connect(connecting, &iname, &oname);
-                        t_sig
-                            .send(Signals::TryConnection(
+                        {
+                            let mut cfg = cfg.write().unwrap();
+                            cfg.connections.connect(connecting, &oname, &iname);
+                            cfg.compile_rules();
+                        }
+                        let _ = t_sig
+                            .send(Signals::ApplyRule(
                                 connecting,
-                                iname.clone(),
                                 oname.clone(),
-                            )).unwrap();
+                                iname.clone(),
+                            )).log_err(&log);
 
                         server::write_response(
                             &log,
                             &server::Response {
-                                ret: 0,
+                                ret: ErrorCode::Ok as i32,
                                 msg: &format!("{}connection", if connecting { "" } else { "dis" }),
                                 obj: json!({
                                 "output_name": &oname,
@@ -414,27 +1061,394 @@ impl ConnectionKit {
                             &mut stream,
                         );
                         drop(stream)
-                        // let _ = stream.write(msg.as_bytes());
-                        // let _ = stream.write(b"\n");
-                        // let _ = stream.flush().log_err(&log);
-                        // info!(log, "{}", msg);
                     }
-                    _ => {
+                    PatchbayCmd::Forbid(switch, oname, iname) => {
+                        if patterns::Pattern::parse(&oname).and(patterns::Pattern::parse(&iname)).is_err() {
+                            server::write_response(
+                                &log,
+                                &server::Response::error(ErrorCode::BadArgs, "Bad pattern!"),
+                                &mut stream,
+                            );
+                            continue;
+                        }
+                        let forbidding = switch.apply(cfg.read().unwrap().forbidden.is_connected(&oname, &iname));
+                        {
+                            let mut cfg = cfg.write().unwrap();
+                            cfg.forbidden.connect(forbidding, &oname, &iname);
+                            cfg.compile_rules();
+                        }
+                        let _ = t_sig.send(Signals::Enforce).log_err(&log);
+
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: if forbidding { "forbidden" } else { "allowed" },
+                                obj: json!({
+                                    "output_name": &oname,
+                                    "input_name":  &iname,
+                                }),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetRules(failing) => {
+                        let jcli = cli.lock().unwrap();
+                        let cfg = cfg.read().unwrap();
+                        let jcli = jcli.as_inactive().ok();
+                        let (outputs, inputs) = jack_ports(jcli);
+                        let (audio_outputs, audio_inputs) = jack_ports_of(jcli, Some("audio"));
+
+                        let mut rules: Vec<Value> = Vec::new();
+                        let rule_sets = vec![
+                            (false, false, &cfg.connections),
+                            (true, false, &cfg.clients),
+                            (false, true, &cfg.forbidden),
+                        ];
+                        for (client, forbidden, rule_set) in rule_sets {
+                            for (oo, iis) in rule_set {
+                                for ii in iis {
+                                    let iis = Some(ii.clone()).into_iter().collect();
+                                    let pairs = if client {
+                                        patterns::client_pairs(oo, &iis, &audio_outputs, &audio_inputs)
+                                    } else {
+                                        patterns::rule_pairs(oo, &iis, &outputs, &inputs)
+                                    }.unwrap_or_default();
+
+                                    let (rule, in_place) = rule_info(jcli, &pairs, oo, ii, client, forbidden);
+                                    if !failing || !in_place {
+                                        rules.push(rule);
+                                    }
+                                }
+                            }
+                        }
+
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "rules",
+                                obj: json!({ "rules": rules }),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetPort(name) => {
+                        let res = {
+                            let jcli = cli.lock().unwrap();
+                            let jcli = jcli.as_inactive().ok();
+                            port_info(jcli, &wanted_pairs(jcli, &cfg.read().unwrap(), &log), &name)
+                        };
+                        let msg = format!("{} info", name);
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Some(obj) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: &msg,
+                                    obj,
+                                },
+                                None => server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetGraph => {
+                        let graph = {
+                            let jcli = cli.lock().unwrap();
+                            let cfg = cfg.read().unwrap();
+                            jcli.as_inactive().ok().map(|jcli| graph_info(jcli, &cfg, &log))
+                        };
+
+                        server::write_response(
+                            &log,
+                            &match graph {
+                                Some(graph) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "graph",
+                                    obj: graph,
+                                },
+                                None => server::Response::error(ErrorCode::Failed, "Not connected to JACK!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetFailures => {
+                        let failures = failures_info(&failures);
+
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "failures",
+                                obj: json!({ "failures": failures }),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetFallbacks => {
+                        let jcli = cli.lock().unwrap();
+                        let cfg = cfg.read().unwrap();
+                        let jcli = jcli.as_inactive().ok();
+                        let (_, inputs) = jack_ports(jcli);
+
+                        let groups: Vec<Value> = fallback_routes(jcli, &cfg.fallbacks, &log)
+                            .into_iter()
+                            .zip(&cfg.fallbacks)
+                            .map(|((active, routes), group)| {
+                                let routes: Vec<Value> = routes
+                                    .iter()
+                                    .zip(group)
+                                    .map(|(pairs, rules)| {
+                                        json!({
+                                            "rules":     rules,
+                                            "available": route_available(pairs, &inputs),
+                                            "connected": !pairs.is_empty() && pairs
+                                                .iter()
+                                                .all(|&(ref o, ref i)| jack_connected(jcli, o, i)),
+                                        })
+                                    }).collect();
+                                json!({
+                                    "active": active,
+                                    "routes": routes,
+                                })
+                            }).collect();
+
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "fallbacks",
+                                obj: json!({ "fallbacks": groups }),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::GetPolicy(name) => {
+                        let enforcement = &cfg.read().unwrap().enforcement;
+                        let obj = match name {
+                            Some(name) => json!({
+                                "name":   name,
+                                "policy": enforcement.policy_of(&name).unwrap_or(enforcement.default),
+                            }),
+                            None => json!({
+                                "default":  enforcement.default,
+                                "policies": enforcement.policies,
+                            }),
+                        };
                         server::write_response(
                             &log,
                             &server::Response {
-                                ret: 1,
-                                msg: "Bad command!",
-                                obj: serde_json::Value::Null,
+                                ret: ErrorCode::Ok as i32,
+                                msg: "policy",
+                                obj,
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::SetPolicy(policy, name) => {
+                        if name.as_ref().map_or(false, |n| patterns::Pattern::parse(n).is_err()) {
+                            server::write_response(
+                                &log,
+                                &server::Response::error(ErrorCode::BadArgs, "Bad pattern!"),
+                                &mut stream,
+                            );
+                            continue;
+                        }
+                        {
+                            let enforcement = &mut cfg.write().unwrap().enforcement;
+                            match (policy, name.clone()) {
+                                (policy, Some(name)) => enforcement.set(name, policy),
+                                (Some(policy), None) => enforcement.default = policy,
+                                (None, None) => (),
+                            }
+                        }
+                        let _ = t_sig.send(Signals::Enforce).log_err(&log);
+
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "policy set",
+                                obj: json!({
+                                    "name":   name,
+                                    "policy": policy,
+                                }),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::ListPorts(ref pattern) | PatchbayCmd::ListClients(ref pattern) => {
+                        let by_client = match command {
+                            PatchbayCmd::ListClients(_) => true,
+                            _ => false,
+                        };
+                        let jcli = cli.lock().unwrap();
+                        let cfg = cfg.read().unwrap();
+
+                        server::write_response(
+                            &log,
+                            &match jcli.as_inactive() {
+                                Ok(jcli) => {
+                                    let wanted = wanted_pairs(Some(jcli), &cfg, &log);
+                                    let ports: Vec<Value> = jcli
+                                        .ports(pattern.as_ref().map(|p| p.as_str()), None, j::PortFlags::empty())
+                                        .iter()
+                                        .filter_map(|p| port_info(Some(jcli), &wanted, p))
+                                        .collect();
+                                    if by_client {
+                                        // Clients in the order their first port shows up
+                                        let mut clients: Vec<Value> = Vec::new();
+                                        for port in ports {
+                                            let client = port["client"].clone();
+                                            let i = match clients.iter().position(|c| c["client"] == client) {
+                                                Some(i) => i,
+                                                None => {
+                                                    clients.push(json!({ "client": client, "ports": [] }));
+                                                    clients.len() - 1
+                                                }
+                                            };
+                                            if let Some(ports) = clients[i]["ports"].as_array_mut() {
+                                                ports.push(port);
+                                            }
+                                        }
+                                        server::Response {
+                                            ret: ErrorCode::Ok as i32,
+                                            msg: "clients",
+                                            obj: json!({ "clients": clients }),
+                                        }
+                                    } else {
+                                        server::Response {
+                                            ret: ErrorCode::Ok as i32,
+                                            msg: "ports",
+                                            obj: json!({ "ports": ports }),
+                                        }
+                                    }
+                                }
+                                Err(_) => server::Response::error(ErrorCode::Failed, "Not connected to JACK!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::Capture(to, clients, generalize) => {
+                        let parsed: Result<Vec<patterns::Pattern>, _> =
+                            clients.iter().map(|c| patterns::Pattern::parse(c)).collect();
+                        let clients = match parsed {
+                            Ok(clients) => clients,
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::BadArgs, "Bad pattern!"),
+                                    &mut stream,
+                                );
+                                continue;
+                            }
+                        };
+                        let rules = {
+                            let jcli = cli.lock().unwrap();
+                            jcli.as_inactive()
+                                .ok()
+                                .map(|jcli| capture_graph(jcli, &clients, generalize))
+                        };
+
+                        let res = match (rules, to) {
+                            (None, _) => Err((ErrorCode::Failed, "Not connected to JACK!")),
+                            (Some(rules), CaptureTo::Show) => Ok((rules, None)),
+                            (Some(rules), CaptureTo::Merge) => {
+                                merge_rules(&cfg, &rules, &t_sig, &log);
+                                Ok((rules, None))
+                            }
+                            (Some(rules), CaptureTo::Save(path)) => {
+                                match config::save_profile(std::path::Path::new(&path), &rules, &log) {
+                                    Ok(()) => Ok((rules, Some(path))),
+                                    Err(()) => Err((ErrorCode::Failed, "Could not write the profile!")),
+                                }
+                            }
+                        };
+
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Ok((rules, path)) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "captured",
+                                    obj: json!({
+                                        "connections": rules,
+                                        "path":        path,
+                                    }),
+                                },
+                                Err((code, msg)) => server::Response::error(code, msg),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::Merge(path) => {
+                        let res = config::load_profile(std::path::Path::new(&path), &log);
+                        if let Ok(ref rules) = res {
+                            merge_rules(&cfg, rules, &t_sig, &log);
+                        }
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Ok(rules) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "merged",
+                                    obj: json!({
+                                        "connections": rules,
+                                        "path":        path,
+                                    }),
+                                },
+                                Err(()) => server::Response::error(ErrorCode::Failed, "Could not read the profile!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::Monitor(filter) => {
+                        // A one-shot subscription, answered by the next
+                        // connection event
+                        info!(log, "Hooking patchbay monitor for {:?}", filter.name);
+                        cfg.read().unwrap().mixer.events.subscribe(
+                            stream,
+                            filter,
+                            true,
+                            log.clone(),
+                        );
+                    }
+                    PatchbayCmd::Subscribe(filter) => {
+                        let desc = format!("{:?}", filter);
+                        let sub = stream.try_clone().log_err(&log).map(|s| {
+                            cfg.read().unwrap().mixer.events.subscribe(s, filter, false, log.clone())
+                        });
+                        server::write_response(
+                            &log,
+                            &match sub {
+                                Ok(sub) => {
+                                    info!(log, "New subscription {} ({})", sub, desc);
+                                    server::Response {
+                                        ret: ErrorCode::Ok as i32,
+                                        msg: "subscribed",
+                                        obj: json!({ "sub": sub }),
+                                    }
+                                }
+                                Err(_) => server::Response::error(ErrorCode::Internal, "Could not subscribe!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    PatchbayCmd::Unsubscribe(id) => {
+                        let res = cfg.read().unwrap().mixer.events.unsubscribe(id, stream.conn());
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Ok(_) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "unsubscribed",
+                                    obj: json!({ "sub": id }),
+                                },
+                                Err(_) => server::Response::error(ErrorCode::NotFound, "Subscription not found!"),
                             },
                             &mut stream,
                         );
-                        drop(stream);
-                        // let msg = format!("Bad command: `{}`", command.cmd);
-                        // let _ = stream.write(msg.as_bytes());
-                        // let _ = stream.write(b"\n");
-                        // let _ = stream.flush().log_err(&log);
-                        // error!(log, "{}", msg);
                     }
                 }
             }
@@ -443,7 +1457,11 @@ impl ConnectionKit {
         Ok(())
     }
 
-    pub fn get_cmd_sender(&self) -> Option<&Sender<(TcpStream, server::Command)>> {
+    pub fn get_cmd_sender(&self) -> Option<&Sender<(server::Stream, server::Command)>> {
         return self.t_cmd.as_ref();
     }
+
+    pub fn get_sig_sender(&self) -> Option<&Sender<Signals>> {
+        return self.t_sig.as_ref();
+    }
 }
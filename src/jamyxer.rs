@@ -4,193 +4,753 @@ extern crate slog;
 
 use std;
 use std::collections::HashMap;
-use std::net::TcpStream;
-use std::sync::mpsc::{channel, Sender};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
 use jack as j;
 
-use serde_json::Value;
-
+use command::{CmdError, MixerCmd};
 use config;
+use jacon;
+use meter;
+use patterns;
+use rtcell::RtCell;
 use server;
+use server::ErrorCode;
+
+use utils::Connections;
+use utils::LogError;
 
 type AM<T> = Arc<Mutex<T>>;
+/// Whoever needs both locks takes this one before the config
 type AMAnyClient = AM<jam::AnyClient>;
 
+/// Rates (in Hz) at which meter frames can be streamed to clients
+const DEFAULT_METER_RATE: f32 = 20.0;
+const MIN_METER_RATE: f32 = 1.0;
+const MAX_METER_RATE: f32 = 100.0;
+
+/// How long to wait for the process callback to let go of a removed port
+const RECLAIM_TRIES: u32 = 200;
+const RECLAIM_INTERVAL_MS: u64 = 5;
+
+const MONO_SIDES: [&str; 1] = ["M"];
+const STEREO_SIDES: [&str; 2] = ["L", "R"];
+
+/// One side of a `Ramp`
+struct RampSide {
+    cur: AtomicU32,
+    target: AtomicU32,
+    step: AtomicU32,
+}
+
+impl RampSide {
+    fn new() -> Self {
+        RampSide {
+            cur: AtomicU32::new(0),
+            target: AtomicU32::new(0),
+            step: AtomicU32::new(0),
+        }
+    }
+
+    /// Call `f` with the index of every one of the `n` frames of the period
+    /// and the gain to apply to it
+    fn run<F: FnMut(usize, f32)>(&self, target: f32, ramp_frames: f32, n: usize, mut f: F) {
+        let mut cur = f32::from_bits(self.cur.load(Ordering::Relaxed));
+        let mut step = f32::from_bits(self.step.load(Ordering::Relaxed));
+        if target.to_bits() != self.target.load(Ordering::Relaxed) {
+            step = (target - cur) / ramp_frames.max(1.0);
+            self.target.store(target.to_bits(), Ordering::Relaxed);
+            self.step.store(step.to_bits(), Ordering::Relaxed);
+        }
+
+        for i in 0..n {
+            if cur != target {
+                cur += step;
+                if (step > 0.0 && cur >= target) || (step <= 0.0 && cur <= target) {
+                    cur = target;
+                }
+            }
+            f(i, cur);
+        }
+        self.cur.store(cur.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Gain actually applied by a `Port::copy_from`, moved linearly towards the
+/// published one over the configured ramp time so that volume and balance
+/// changes don't click.
+///
+/// Only ever touched by the process callback, but shared between snapshots
+/// so that ramps carry over when the mix is rebuilt.
+pub struct Ramp {
+    sides: [RampSide; 2],
+}
+
+impl Ramp {
+    pub fn new() -> Self {
+        Ramp {
+            sides: [RampSide::new(), RampSide::new()],
+        }
+    }
+}
+
+type Ramps = HashMap<String, Arc<Ramp>>;
+
+struct Channel {
+    port: j::Port<jam::AnySpec>,
+    meter: Arc<meter::Meter>,
+}
+
 pub struct Port {
     is_mono: bool,
     is_output: bool,
-    ports: HashMap<String, j::Port<jam::AnySpec>>,
+    // One channel (`M`) for mono ports, two (`L` then `R`) for stereo ones
+    channels: Vec<Channel>,
 }
 
 impl Port {
-    pub fn register(name: &str, output: bool, mono: bool, cli: &jam::AnyClient) -> Self {
-        let mut ports = HashMap::new();
+    pub fn register(
+        name: &str,
+        output: bool,
+        mono: bool,
+        cli: &jam::AnyClient,
+    ) -> Result<Self, jam::JamyxErr> {
         let spec = if output {
             jam::AnySpec::AudioOut
         } else {
             jam::AnySpec::AudioIn
         };
 
-        if mono {
-            let pn = format!("{} M", name);
-            let port = cli.as_inactive().unwrap().register_port(&pn, spec).unwrap();
-            ports.insert("M".to_string(), port);
-        } else {
-            let pnl = format!("{} L", name);
-            let pnr = format!("{} R", name);
-            let portl = cli
-                .as_inactive()
-                .unwrap()
-                .register_port(&pnl, spec)
-                .unwrap();
-            let portr = cli
-                .as_inactive()
-                .unwrap()
-                .register_port(&pnr, spec)
-                .unwrap();
-            ports.insert("L".to_string(), portl);
-            ports.insert("R".to_string(), portr);
-        }
-        Self {
+        let sides: &[&str] = if mono { &MONO_SIDES } else { &STEREO_SIDES };
+        let mut channels = Vec::new();
+        for side in sides {
+            channels.push(Channel {
+                port: cli
+                    .as_inactive()?
+                    .register_port(&format!("{} {}", name, side), spec)?,
+                meter: Arc::new(meter::Meter::new()),
+            });
+        }
+
+        Ok(Self {
             is_mono: mono,
             is_output: output,
-            ports,
-        }
+            channels,
+        })
     }
 
-    pub fn register_output(name: &str, mono: bool, cli: &jam::AnyClient) -> Self {
+    pub fn register_output(
+        name: &str,
+        mono: bool,
+        cli: &jam::AnyClient,
+    ) -> Result<Self, jam::JamyxErr> {
         Self::register(name, true, mono, cli)
     }
 
-    pub fn register_input(name: &str, mono: bool, cli: &jam::AnyClient) -> Self {
+    pub fn register_input(
+        name: &str,
+        mono: bool,
+        cli: &jam::AnyClient,
+    ) -> Result<Self, jam::JamyxErr> {
         Self::register(name, false, mono, cli)
     }
 
-    pub fn zero(&mut self, ps: &j::ProcessScope) {
+    pub fn unregister(self, cli: &jam::AnyClient) -> Result<(), jam::JamyxErr> {
+        for ch in self.channels {
+            cli.as_inactive()?.unregister_port(ch.port)?;
+        }
+        Ok(())
+    }
+
+    pub fn rename(&mut self, name: &str) -> Result<(), jam::JamyxErr> {
+        let sides = self.sides();
+        for (side, ch) in sides.iter().zip(self.channels.iter_mut()) {
+            ch.port.set_name(&format!("{} {}", name, side))?;
+        }
+        Ok(())
+    }
+
+    /// Full JACK names of the ports of the channel
+    pub fn jack_names(&self) -> Vec<String> {
+        self.channels
+            .iter()
+            .filter_map(|ch| ch.port.name().ok().map(|n| n.to_owned()))
+            .collect()
+    }
+
+    /// Take back sole ownership of a port that was just removed from the mix,
+    /// waiting for the process callback to let go of the snapshots using it
+    fn reclaim(mut port: Arc<Self>, mix: &RtCell<Mix>) -> Result<Self, Arc<Self>> {
+        for _ in 0..RECLAIM_TRIES {
+            match Arc::try_unwrap(port) {
+                Ok(p) => return Ok(p),
+                Err(p) => port = p,
+            }
+            mix.collect();
+            thread::sleep(Duration::from_millis(RECLAIM_INTERVAL_MS));
+        }
+        Arc::try_unwrap(port)
+    }
+
+    fn sides(&self) -> &'static [&'static str] {
+        if self.is_mono {
+            &MONO_SIDES
+        } else {
+            &STEREO_SIDES
+        }
+    }
+
+    pub fn meters(&self) -> meter::PortMeters {
+        self.sides()
+            .iter()
+            .zip(&self.channels)
+            .map(|(side, ch)| (side.to_string(), ch.meter.clone()))
+            .collect()
+    }
+
+    /// Update the meters with the current contents of the port buffers
+    pub fn meter(&self, ballistics: (f32, f32), ps: &j::ProcessScope) {
+        for ch in &self.channels {
+            ch.meter
+                .update(&jam::AnyAudioInPort::new(&ch.port, ps), ballistics);
+        }
+    }
+
+    pub fn zero(&self, ps: &j::ProcessScope) {
         if !self.is_output {
             return; /* TODO: Panic here or something */
         }
-        match self.is_mono {
-            true => {
-                let ref mut port = self.ports.get_mut("M").unwrap();
-                let mut oport = jam::AnyAudioOutPort::new(port, ps);
-                for e in oport.iter_mut() {
-                    *e = 0.0 as f32;
-                }
-            }
-            false => {
-                {
-                    let ref mut port_l = self.ports.get_mut("L").unwrap();
-                    let mut oport_l = jam::AnyAudioOutPort::new(port_l, ps);
-                    for e in oport_l.iter_mut() {
-                        *e = 0.0 as f32;
-                    }
-                }
-                {
-                    let ref mut port_r = self.ports.get_mut("R").unwrap();
-                    let mut oport_r = jam::AnyAudioOutPort::new(port_r, ps);
-                    for e in oport_r.iter_mut() {
-                        *e = 0.0 as f32;
-                    }
-                }
+        for ch in &self.channels {
+            let mut oport = jam::AnyAudioOutPort::new(&ch.port, ps);
+            for e in oport.iter_mut() {
+                *e = 0.0;
             }
         }
     }
 
     pub fn copy_from(
-        &mut self,
+        &self,
         other: &Self,
         vol: f32,
         balance: (f32, f32),
+        ramp: &Ramp,
+        ramp_frames: f32,
         ps: &j::ProcessScope,
-        _: &slog::Logger,
     ) {
         if !self.is_output {
             return; /* TODO: Panic here or something */
         }
+        let n = ps.n_frames() as usize;
 
         if self.is_mono {
-            let ref mut port = self.ports.get_mut("M").unwrap();
-            let mut oport = jam::AnyAudioOutPort::new(port, ps);
+            // === MONO/STEREO TO MONO ===
+            let mut oport = jam::AnyAudioOutPort::new(&self.channels[0].port, ps);
+            let other_p = jam::AnyAudioInPort::new(&other.channels[0].port, ps);
             if other.is_mono {
-                // === MONO TO MONO ===
-                let other_p = jam::AnyAudioInPort::new(&other.ports["M"], ps);
-                // oport.clone_from_slice(&other_p);
-                for (i, e) in oport.iter_mut().enumerate() {
-                    *e += other_p[i] * vol;
-                }
+                ramp.sides[0].run(vol, ramp_frames, n, |i, g| oport[i] += other_p[i] * g);
             } else {
-                // === STEREO TO MONO ===
-                let other_p_l = jam::AnyAudioInPort::new(&other.ports["L"], ps);
-                let other_p_r = jam::AnyAudioInPort::new(&other.ports["R"], ps);
-                // oport.clone_from_slice(&other_p_l);
-                for (i, e) in oport.iter_mut().enumerate() {
-                    *e += other_p_l[i] * vol;
-                }
-                for (i, e) in oport.iter_mut().enumerate() {
-                    *e += other_p_r[i] * vol; // Multiply???
-                }
+                let other_p_r = jam::AnyAudioInPort::new(&other.channels[1].port, ps);
+                ramp.sides[0].run(vol, ramp_frames, n, |i, g| {
+                    oport[i] += (other_p[i] + other_p_r[i]) * g
+                });
             }
         } else {
-            // to left channel
-            {
-                let ref mut port_l = self.ports.get_mut("L").unwrap();
-                let mut oport_l = jam::AnyAudioOutPort::new(port_l, ps);
-                if other.is_mono {
-                    // === MONO TO STEREO ===
-                    let other_p = jam::AnyAudioInPort::new(&other.ports["M"], ps);
-                    // oport_l.copy_from_slice(&other_p);
-                    for (i, e) in oport_l.iter_mut().enumerate() {
-                        *e += other_p[i] * vol * balance.0;
-                    }
-                } else {
-                    // === STEREO TO STEREO ===
-                    let other_p_l = jam::AnyAudioInPort::new(&other.ports["L"], ps);
-                    // oport_l.copy_from_slice(&other_p_l);
-                    for (i, e) in oport_l.iter_mut().enumerate() {
-                        *e += other_p_l[i] * vol * balance.0;
-                    }
-                }
+            // === MONO/STEREO TO STEREO ===
+            let gains = [vol * balance.0, vol * balance.1];
+            for (side, ch) in self.channels.iter().enumerate() {
+                let mut oport = jam::AnyAudioOutPort::new(&ch.port, ps);
+                // Mono sources feed both sides
+                let src = &other.channels[if other.is_mono { 0 } else { side }];
+                let other_p = jam::AnyAudioInPort::new(&src.port, ps);
+                ramp.sides[side].run(gains[side], ramp_frames, n, |i, g| {
+                    oport[i] += other_p[i] * g
+                });
             }
-            // to right channel
-            {
-                let ref mut port_r = self.ports.get_mut("R").unwrap();
-                let mut oport_r = jam::AnyAudioOutPort::new(port_r, ps);
-                if other.is_mono {
-                    // === MONO TO STEREO ===
-                    let other_p = jam::AnyAudioInPort::new(&other.ports["M"], ps);
-                    // oport_r.copy_from_slice(&other_p);
-                    for (i, e) in oport_r.iter_mut().enumerate() {
-                        *e += other_p[i] * vol * balance.1;
-                    }
-                } else {
-                    // === STEREO TO STEREO ===
-                    let other_p_r = jam::AnyAudioInPort::new(&other.ports["R"], ps);
-                    // oport_r.copy_from_slice(&other_p_r);
-                    for (i, e) in oport_r.iter_mut().enumerate() {
-                        *e += other_p_r[i] * vol * balance.1;
-                    }
+        }
+    }
+}
+
+/// The JACK ports registered by the mixer, by channel name
+pub struct PortSet {
+    inputs: HashMap<String, Arc<Port>>,
+    input_outs: HashMap<String, Arc<Port>>,
+    outputs: HashMap<String, Arc<Port>>,
+    monitor: Option<Arc<Port>>,
+}
+
+impl PortSet {
+    pub fn new() -> Self {
+        PortSet {
+            inputs: HashMap::new(),
+            input_outs: HashMap::new(),
+            outputs: HashMap::new(),
+            monitor: None,
+        }
+    }
+
+    /// Register the JACK ports of a new mixer channel
+    pub fn add(
+        &mut self,
+        is_output: bool,
+        name: &str,
+        mono: bool,
+        cli: &jam::AnyClient,
+    ) -> Result<(), jam::JamyxErr> {
+        if is_output {
+            let port = Port::register_output(name, mono, cli)?;
+            self.outputs.insert(name.to_owned(), Arc::new(port));
+        } else {
+            let port = Port::register_input(name, mono, cli)?;
+            let out = Port::register_output(&format!("{} Out", name), mono, cli)?;
+            self.inputs.insert(name.to_owned(), Arc::new(port));
+            self.input_outs.insert(name.to_owned(), Arc::new(out));
+        }
+        Ok(())
+    }
+
+    /// Take the ports of a mixer channel out of the set: the port itself and,
+    /// for inputs, its `<NAME> Out` port
+    pub fn take(&mut self, is_output: bool, name: &str) -> Vec<Arc<Port>> {
+        if is_output {
+            self.outputs.remove(name).into_iter().collect()
+        } else {
+            self.inputs
+                .remove(name)
+                .into_iter()
+                .chain(self.input_outs.remove(name))
+                .collect()
+        }
+    }
+
+    /// Put back ports obtained from `take`, possibly under a new name
+    pub fn put(&mut self, is_output: bool, name: &str, ports: Vec<Arc<Port>>) {
+        let mut ports = ports.into_iter();
+        if is_output {
+            if let Some(port) = ports.next() {
+                self.outputs.insert(name.to_owned(), port);
+            }
+        } else {
+            if let Some(port) = ports.next() {
+                self.inputs.insert(name.to_owned(), port);
+            }
+            if let Some(out) = ports.next() {
+                self.input_outs.insert(name.to_owned(), out);
+            }
+        }
+    }
+
+    pub fn meter_bank(&self) -> meter::MeterBank {
+        let mut bank = meter::MeterBank::new();
+        for (name, port) in &self.inputs {
+            bank.inputs.insert(name.clone(), port.meters());
+        }
+        for (name, port) in &self.outputs {
+            bank.outputs.insert(name.clone(), port.meters());
+        }
+        if let Some(ref port) = self.monitor {
+            bank.monitor = port.meters();
+        }
+        bank
+    }
+}
+
+/// An input channel strip: the input port and its post-fader `<NAME> Out`
+/// port
+struct Strip {
+    port: Arc<Port>,
+    out: Arc<Port>,
+    vol: f32,
+    balance: (f32, f32),
+    ramp: Arc<Ramp>,
+}
+
+/// What an input strip (by index) contributes to an output bus
+struct Feed {
+    input: usize,
+    vol: f32,
+    balance: (f32, f32),
+    ramp: Arc<Ramp>,
+}
+
+struct Bus {
+    port: Arc<Port>,
+    feeds: Vec<Feed>,
+}
+
+struct Tap {
+    port: Arc<Port>,
+    source: Option<(Arc<Port>, f32, (f32, f32))>,
+    ramp: Arc<Ramp>,
+}
+
+/// Snapshot of the mixer as seen by the process callback.
+///
+/// Rebuilt from the config and the registered ports whenever either of them
+/// changes and handed to the realtime thread through an `RtCell`, so that
+/// processing a period never locks, allocates or looks anything up by name.
+pub struct Mix {
+    strips: Vec<Strip>,
+    buses: Vec<Bus>,
+    monitor: Option<Tap>,
+    ramp_ms: f32,
+}
+
+impl Mix {
+    pub fn empty() -> Self {
+        Mix {
+            strips: Vec::new(),
+            buses: Vec::new(),
+            monitor: None,
+            ramp_ms: 0.0,
+        }
+    }
+
+    /// `ramps` holds the ramps of the previous snapshot; the ones that are
+    /// still in use are carried over and the rest are dropped.
+    pub fn build(cfg: &config::MixerConfig, ports: &PortSet, ramps: &mut Ramps) -> Self {
+        let combine_balance = |a: (f32, f32), b: (f32, f32)| (a.0 * b.0, a.1 * b.1);
+        let downmix = |dst: &Port, src: &Port| {
+            if dst.is_mono && !src.is_mono {
+                cfg.downmix
+            } else {
+                1.0
+            }
+        };
+
+        let old_ramps = std::mem::replace(ramps, HashMap::new());
+        let mut ramp = |key: String| {
+            let r = old_ramps
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Ramp::new()));
+            ramps.insert(key, r.clone());
+            r
+        };
+
+        let mut strips = Vec::new();
+        let mut strip_idx = HashMap::new();
+        for (name, config) in &cfg.inputs {
+            if let (Some(port), Some(out)) = (ports.inputs.get(name), ports.input_outs.get(name)) {
+                strip_idx.insert(name, strips.len());
+                strips.push(Strip {
+                    port: port.clone(),
+                    out: out.clone(),
+                    vol: cfg.get_gain(false, name).unwrap(),
+                    balance: config.get_balance_pair(cfg.pan_law),
+                    ramp: ramp(format!("in:{}", name)),
+                });
+            }
+        }
+
+        let mut buses = Vec::new();
+        for (name, config) in &cfg.outputs {
+            if let Some(port) = ports.outputs.get(name) {
+                let vol = cfg.get_gain(true, name).unwrap();
+                let balance = config.get_balance_pair(cfg.pan_law);
+                let feeds = cfg
+                    .connections
+                    .get(name)
+                    .into_iter()
+                    .flat_map(|is| is.iter())
+                    .filter_map(|i| strip_idx.get(i).map(|&input| (i, input)))
+                    .map(|(i, input)| Feed {
+                        input,
+                        vol: strips[input].vol
+                            * vol
+                            * cfg.get_send_gain(name, i).unwrap()
+                            * downmix(port, &strips[input].port),
+                        balance: combine_balance(strips[input].balance, balance),
+                        ramp: ramp(format!("feed:{}:{}", name, i)),
+                    }).collect();
+                buses.push(Bus {
+                    port: port.clone(),
+                    feeds,
+                });
+            }
+        }
+
+        let monitor = ports.monitor.as_ref().map(|port| {
+            let is_output = !cfg.monitor.is_input;
+            let name = &cfg.monitor.channel;
+            let source = match if is_output {
+                ports.outputs.get(name)
+            } else {
+                ports.inputs.get(name)
+            } {
+                Some(src) => match (
+                    cfg.get_gain(is_output, name),
+                    cfg.get_bal_pair(is_output, name),
+                ) {
+                    (Ok(vol), Ok(balance)) => Some((src.clone(), vol, balance)),
+                    _ => None,
+                },
+                None => None,
+            };
+            Tap {
+                port: port.clone(),
+                source,
+                ramp: ramp("monitor".to_owned()),
+            }
+        });
+
+        Mix {
+            strips,
+            buses,
+            monitor,
+            ramp_ms: cfg.ramp_ms,
+        }
+    }
+
+    /// Run one period of the mixer. Called from the realtime thread.
+    pub fn process(&self, ballistics: (f32, f32), sample_rate: usize, ps: &j::ProcessScope) {
+        let ramp_frames = self.ramp_ms / 1000.0 * sample_rate as f32;
+
+        for strip in &self.strips {
+            strip.port.meter(ballistics, ps);
+            strip.out.zero(ps);
+            strip.out.copy_from(
+                &strip.port,
+                strip.vol,
+                strip.balance,
+                &strip.ramp,
+                ramp_frames,
+                ps,
+            );
+        }
+
+        for bus in &self.buses {
+            bus.port.zero(ps);
+            for feed in &bus.feeds {
+                bus.port.copy_from(
+                    &self.strips[feed.input].port,
+                    feed.vol,
+                    feed.balance,
+                    &feed.ramp,
+                    ramp_frames,
+                    ps,
+                );
+            }
+            bus.port.meter(ballistics, ps);
+        }
+
+        if let Some(ref tap) = self.monitor {
+            tap.port.zero(ps);
+            if let Some((ref src, vol, balance)) = tap.source {
+                tap.port
+                    .copy_from(src, vol, balance, &tap.ramp, ramp_frames, ps);
+            }
+            tap.port.meter(ballistics, ps);
+        }
+    }
+}
+
+/// Ports of `cfg` that don't exist in `other`, or do but with a different
+/// number of channels, as `(is_output, name, mono)`
+fn changed_ports(cfg: &config::MixerConfig, other: &config::MixerConfig) -> Vec<(bool, String, bool)> {
+    let mut changed = Vec::new();
+    for &(is_output, ports, other_ports) in &[
+        (false, &cfg.inputs, &other.inputs),
+        (true, &cfg.outputs, &other.outputs),
+    ] {
+        for (name, port) in ports {
+            if other_ports.get(name).map(|p| p.is_mono()) != Some(port.is_mono()) {
+                changed.push((is_output, name.clone(), port.is_mono()));
+            }
+        }
+    }
+    changed
+}
+
+/// What it takes to add and remove channels, shared by the command thread
+/// and the automatic channels
+#[derive(Clone)]
+struct Channels {
+    log: slog::Logger,
+    cli: AMAnyClient,
+    cfg: Arc<RwLock<config::Config>>,
+    ports: AM<PortSet>,
+    ramps: AM<Ramps>,
+    mix: Arc<RtCell<Mix>>,
+    meters: Arc<RwLock<meter::MeterBank>>,
+}
+
+impl Channels {
+    /// Hand the updated mixer over to the process callback
+    fn publish(&self) {
+        self.mix.publish(Mix::build(
+            &self.cfg.read().unwrap().mixer,
+            &self.ports.lock().unwrap(),
+            &mut self.ramps.lock().unwrap(),
+        ))
+    }
+
+    fn update_meters(&self) {
+        *self.meters.write().unwrap() = self.ports.lock().unwrap().meter_bank();
+    }
+
+    fn create(&self, is_output: bool, name: &String, mono: bool) -> Result<(), CmdError> {
+        let added = self.cfg.write().unwrap().mixer.add_port(is_output, name, mono);
+        added.map_err(|_| (ErrorCode::Failed, "Port already exists!"))?;
+
+        // No config lock while registering, Jacon takes `cli` before `cfg`
+        let registered = {
+            let cli = self.cli.lock().unwrap();
+            let mut ports = self.ports.lock().unwrap();
+            ports.add(is_output, name, mono, &cli)
+        };
+        if registered.log_err(&self.log).is_err() {
+            let _ = self.cfg.write().unwrap().mixer.remove_port(is_output, name);
+            return Err((ErrorCode::Failed, "Could not register port!"));
+        }
+
+        info!(self.log, "Created {} port `{}`", if is_output { "out" } else { "in" }, name);
+        self.update_meters();
+        self.publish();
+        Ok(())
+    }
+
+    fn remove(&self, is_output: bool, name: &String) -> Result<(), ()> {
+        self.cfg.write().unwrap().mixer.remove_port(is_output, name)?;
+
+        let taken = self.ports.lock().unwrap().take(is_output, name);
+        self.update_meters();
+        self.publish();
+        for port in taken {
+            match Port::reclaim(port, &self.mix) {
+                Ok(port) => {
+                    let _ = port.unregister(&self.cli.lock().unwrap()).log_err(&self.log);
                 }
+                Err(_) => {
+                    error!(self.log, "Port `{}` still in use, leaving it registered", name);
+                }
+            }
+        }
+        info!(self.log, "Removed {} port `{}`", if is_output { "out" } else { "in" }, name);
+        Ok(())
+    }
+}
+
+/// Rename a port of a channel and the patchbay rules for its JACK ports
+fn rename_port(port: &mut Port, name: &str, cfg: &RwLock<config::Config>, log: &slog::Logger) -> Result<(), ()> {
+    let old_names = port.jack_names();
+    let res = port.rename(name).log_err(log).map_err(|_| ());
+    let mut cfg = cfg.write().unwrap();
+    for (old, new) in old_names.iter().zip(port.jack_names()) {
+        if *old != new {
+            cfg.rename_jack_port(old, &new);
+        }
+    }
+    res
+}
+
+/// Rename the ports of a channel, the second one being the `Out` port of an
+/// input. If one of them fails, the ones already renamed get their old name
+/// back, so that the JACK ports and the rules keep matching the channel
+fn rename_channel_ports(
+    ports: &mut [Port],
+    old_name: &str,
+    new_name: &str,
+    cfg: &RwLock<config::Config>,
+    log: &slog::Logger,
+) -> Result<(), ()> {
+    let port_name = |name: &str, i: usize| if i == 0 { name.to_owned() } else { format!("{} Out", name) };
+    let failed = (0..ports.len()).find(|&i| rename_port(&mut ports[i], &port_name(new_name, i), cfg, log).is_err());
+    match failed {
+        Some(failed) => {
+            for (i, port) in ports.iter_mut().enumerate().take(failed + 1) {
+                let _ = rename_port(port, &port_name(old_name, i), cfg, log);
             }
+            Err(())
         }
+        None => Ok(()),
     }
 }
 
+/// Create an input channel for a client that just appeared, routed from its
+/// outputs by a client rule and into the default buses. The rule added for
+/// it is kept in `created`
+fn auto_create(channels: &Channels, created: &mut HashMap<String, (String, String)>, client: &str) {
+    // `cli` before `cfg`, and never both at once here
+    let own = match channels.cli.lock().unwrap().as_inactive() {
+        Ok(cli) => cli.name().to_owned(),
+        Err(_) => return,
+    };
+    let auto = channels.cfg.read().unwrap().mixer.auto.clone();
+    let wanted = auto.clients.iter().any(|p| {
+        patterns::Pattern::parse(p)
+            .map(|p| p.is_match(client))
+            .unwrap_or(false)
+    });
+    if !wanted || client == own {
+        return;
+    }
+
+    // Client names can hold anything, pattern syntax included
+    let name = client.to_owned();
+    let src = patterns::exact(&name);
+    let dest = format!("{}:{}", own, patterns::escape_refs(&name));
+    {
+        let mut cfg = channels.cfg.write().unwrap();
+        if cfg.mixer.port_exists(false, &name) {
+            // Kept from an earlier run of the client, or configured by hand
+            return;
+        }
+        // Route the client first, so that Jacon connects the ports as soon
+        // as they are registered
+        cfg.clients.connect(true, &src, &dest);
+    }
+    if channels.create(false, &name, auto.mono).log_err(&channels.log).is_err() {
+        channels.cfg.write().unwrap().clients.connect(false, &src, &dest);
+        return;
+    }
+    created.insert(name.clone(), (src, dest));
+
+    {
+        let mut cfg = channels.cfg.write().unwrap();
+        for bus in &auto.buses {
+            if cfg.mixer.port_exists(true, bus) {
+                let _ = cfg.mixer.connect(true, bus, &name);
+            }
+        }
+    }
+    channels.publish();
+}
+
+/// Remove the channel of a client that went away, along with the rule added
+/// for it, unless it is to be kept
+fn auto_remove(channels: &Channels, created: &mut HashMap<String, (String, String)>, client: &str) {
+    if channels.cfg.read().unwrap().mixer.auto.keep {
+        return;
+    }
+    let (src, dest) = match created.remove(client) {
+        Some(rule) => rule,
+        None => return,
+    };
+
+    channels.cfg.write().unwrap().clients.connect(false, &src, &dest);
+    let _ = channels.remove(false, &client.to_owned());
+}
+
 pub struct Patchbay {
     log: slog::Logger,
     cli: AMAnyClient,
     cfg: Arc<RwLock<config::Config>>,
-    inputs: AM<HashMap<String, Port>>,
-    input_outs: AM<HashMap<String, Port>>,
-    outputs: AM<HashMap<String, Port>>,
+    ports: AM<PortSet>,
+    ramps: AM<Ramps>,
+    mix: Arc<RtCell<Mix>>,
+    meters: Arc<RwLock<meter::MeterBank>>,
 
-    monitor_port: AM<Option<Port>>,
-
-    t_cmd: Option<Sender<(TcpStream, server::Command)>>,
+    t_jacon: Option<Sender<jacon::Signals>>,
+    t_cmd: Option<Sender<(server::Stream, server::Command)>>,
     cmd_thread: Option<std::thread::JoinHandle<()>>,
+    // Clients (un)registering, for the automatic channels
+    r_auto: Option<Receiver<(String, bool)>>,
+    auto_thread: Option<std::thread::JoinHandle<()>>,
 }
 
 impl Patchbay {
@@ -199,180 +759,175 @@ impl Patchbay {
             log,
             cli,
             cfg,
-            inputs: Arc::new(Mutex::new(HashMap::new())),
-            input_outs: Arc::new(Mutex::new(HashMap::new())),
-            outputs: Arc::new(Mutex::new(HashMap::new())),
-
-            monitor_port: Arc::new(Mutex::new(None)),
+            ports: Arc::new(Mutex::new(PortSet::new())),
+            ramps: Arc::new(Mutex::new(HashMap::new())),
+            mix: Arc::new(RtCell::new(Mix::empty())),
+            meters: Arc::new(RwLock::new(meter::MeterBank::new())),
 
+            t_jacon: None,
             t_cmd: None,
             cmd_thread: None,
+            r_auto: None,
+            auto_thread: None,
         }
     }
 
     pub fn init(&mut self, jclient: &mut jam::Client) {
-        let ins = self.inputs.clone();
-        let ios = self.input_outs.clone();
-        let outs = self.outputs.clone();
         let cfg = self.cfg.clone();
         let cli = self.cli.clone();
-        let mon_port = self.monitor_port.clone();
+        let ports = self.ports.clone();
+        let ramps = self.ramps.clone();
+        let mix = self.mix.clone();
+        let meters = self.meters.clone();
 
         let register_ports = move || {
-            for (ref name, ref config) in &cfg.read().unwrap().mixer.inputs {
-                ins.lock().unwrap().insert(
-                    name.clone().to_owned(),
-                    Port::register_input(&name, config.is_mono(), &cli.lock().unwrap()),
+            // `cli` before `cfg`, as everywhere else
+            let jcli = cli.lock().unwrap();
+            let cfg = cfg.read().unwrap();
+            let mut set = PortSet::new();
+
+            for (name, config) in &cfg.mixer.inputs {
+                set.inputs.insert(
+                    name.clone(),
+                    Arc::new(
+                        Port::register_input(&name, config.is_mono(), &jcli)
+                            .unwrap(),
+                    ),
                 );
-                ios.lock().unwrap().insert(
-                    name.clone().to_owned(),
-                    Port::register_output(
-                        &format!("{} Out", name),
-                        config.is_mono(),
-                        &cli.lock().unwrap(),
+                set.input_outs.insert(
+                    name.clone(),
+                    Arc::new(
+                        Port::register_output(
+                            &format!("{} Out", name),
+                            config.is_mono(),
+                            &jcli,
+                        ).unwrap(),
                     ),
                 );
             }
 
-            for (ref name, ref config) in &cfg.read().unwrap().mixer.outputs {
-                outs.lock().unwrap().insert(
-                    name.clone().to_owned(),
-                    Port::register_output(&name, config.is_mono(), &cli.lock().unwrap()),
+            for (name, config) in &cfg.mixer.outputs {
+                set.outputs.insert(
+                    name.clone(),
+                    Arc::new(
+                        Port::register_output(&name, config.is_mono(), &jcli)
+                            .unwrap(),
+                    ),
                 );
             }
 
-            let monitor_port = Port::register_output("MONITOR", false, &cli.lock().unwrap());
-            *mon_port.lock().unwrap() = Some(monitor_port);
+            set.monitor = Some(Arc::new(
+                Port::register_output("MONITOR", false, &jcli).unwrap(),
+            ));
+
+            *meters.write().unwrap() = set.meter_bank();
+            mix.publish(Mix::build(&cfg.mixer, &set, &mut ramps.lock().unwrap()));
+            *ports.lock().unwrap() = set;
         };
         register_ports();
         jclient.hook(jam::CB::client_reconnection(Box::new(register_ports)));
 
-        // Hook process callback
-        let log = self.log.clone();
-        let ins = self.inputs.clone();
-        let ios = self.input_outs.clone();
-        let outs = self.outputs.clone();
-        let cfg = self.cfg.clone();
-        let monitor_port = self.monitor_port.clone();
-        jclient.hook(jam::CB::process(Box::new(move |_, scope| {
-            // debug!(log, "test: {:?}", cfg.lock().unwrap().mixer.outputs);
-            // debug!(log, "PROC");
-            let combine_balance = |a: (f32, f32), b: (f32, f32)| (a.0 * b.0, a.1 * b.1);
-
-            // let cfg = cfg.lock().unwrap().clone();
-            for (ref i, ref config) in &cfg.read().unwrap().mixer.inputs {
-                ios.lock().unwrap().get_mut(*i).unwrap().zero(&scope);
-                ios.lock().unwrap().get_mut(*i).unwrap().copy_from(
-                    &ins.lock().unwrap()[*i],
-                    config.get_vol() as f32,
-                    config.get_balance_pair(),
-                    &scope,
-                    &log,
-                );
-            }
-
-            for (ref o, ref is) in &cfg.read().unwrap().mixer.connections {
-                outs.lock().unwrap().get_mut(*o).unwrap().zero(&scope);
-                for ref i in is.iter() {
-                    let cfg = cfg.read().unwrap();
-
-                    outs.lock().unwrap().get_mut(*o).unwrap().copy_from(
-                        &ins.lock().unwrap()[*i],
-                        cfg.mixer.inputs[*i].get_vol() as f32
-                            * cfg.mixer.outputs[*o].get_vol() as f32,
-                        combine_balance(
-                            cfg.mixer.inputs[*i].get_balance_pair(),
-                            cfg.mixer.outputs[*o].get_balance_pair(),
-                        ),
-                        &scope,
-                        &log,
-                    );
-                }
-            }
+        // Hook client_registration, for the automatic channels (ports can't
+        // be registered from the notification thread)
+        let (t_auto, r_auto) = channel();
+        self.r_auto = Some(r_auto);
+        jclient.hook(jam::CB::client_registration(Box::new(move |_, cn, of| {
+            let _ = t_auto.send((cn.to_owned(), of));
+        })));
 
-            {
-                let is_output = !cfg.read().unwrap().mixer.monitor.is_input;
-                // let config = cfg.read().unwrap().mixer.get_vol
-                let ref moned_port_name = cfg.read().unwrap().mixer.monitor.channel;
-                let ref moned_port = match cfg.read().unwrap().mixer.monitor.is_input {
-                    true => ins.lock().unwrap(),
-                    false => outs.lock().unwrap(),
-                }[moned_port_name];
-                // debug!(log, "copying mon... {} to monitor port", moned_port_name);
-                monitor_port.lock().unwrap().as_mut().unwrap().zero(&scope);
-                monitor_port.lock().unwrap().as_mut().unwrap().copy_from(
-                    moned_port,
-                    cfg.read()
-                        .unwrap()
-                        .mixer
-                        .get_vol(is_output, moned_port_name)
-                        .unwrap(),
-                    cfg.read()
-                        .unwrap()
-                        .mixer
-                        .get_bal_pair(is_output, moned_port_name)
-                        .unwrap(),
-                    &scope,
-                    &log,
-                );
-            }
+        // Hook process callback
+        let mix = self.mix.clone();
+        jclient.hook(jam::CB::process(Box::new(move |c, scope| {
+            let sample_rate = c.sample_rate();
+            let ballistics = meter::Meter::ballistics(scope.n_frames(), sample_rate);
+            mix.with(|mix| mix.process(ballistics, sample_rate, scope));
 
             return j::Control::Continue;
         })));
     }
 
+    /// Give the mixer a way to have Jacon redo the patchbay connections,
+    /// e.g. after loading a new config
+    pub fn set_jacon(&mut self, t_sig: Sender<jacon::Signals>) {
+        self.t_jacon = Some(t_sig);
+    }
+
     pub fn start(&mut self) {
         let (_t_cmd, r_cmd) = channel();
+        let t_jacon = self.t_jacon.clone();
         let cfg = self.cfg.clone();
         let log = self.log.clone();
+        let meters = self.meters.clone();
+        let ports = self.ports.clone();
+        let mix = self.mix.clone();
+        let cli = self.cli.clone();
+
+        let channels = Channels {
+            log: self.log.clone(),
+            cli: self.cli.clone(),
+            cfg: self.cfg.clone(),
+            ports: self.ports.clone(),
+            ramps: self.ramps.clone(),
+            mix: self.mix.clone(),
+            meters: self.meters.clone(),
+        };
+
+        if let Some(r_auto) = self.r_auto.take() {
+            let channels = channels.clone();
+            self.auto_thread = Some(thread::spawn(move || {
+                let mut created = HashMap::new();
+                for (client, of) in r_auto {
+                    if of {
+                        auto_create(&channels, &mut created, &client);
+                    } else {
+                        auto_remove(&channels, &mut created, &client);
+                    }
+                }
+            }));
+        }
 
         self.t_cmd = Some(_t_cmd.clone());
         self.cmd_thread = Some(thread::spawn(move || {
+            let publish = || channels.publish();
+            let update_meters = || channels.update_meters();
+
             loop {
-                let (mut stream, command): (TcpStream, server::Command) = r_cmd.recv().unwrap();
-                let get_ptype = |pt: &String| match &**pt {
-                    "input" | "in" | "i" => false,
-                    "output" | "out" | "o" | _ => true, // TODO: Handle bad args to server commands
+                let (mut stream, command): (server::Stream, server::Command) = r_cmd.recv().unwrap();
+                let command = match MixerCmd::parse(&command) {
+                    Ok(command) => command,
+                    Err((code, msg)) => {
+                        warn!(log, "{} ({} `{}` {:?})", msg, command.target, command.cmd, command.opts);
+                        server::write_response(&log, &server::Response::error(code, msg), &mut stream);
+                        continue;
+                    }
                 };
+                let changes_mix = command.changes_mix();
 
-                match command.cmd.as_str() {
-                    "con" | "dis" | "tog" => {
-                        let iname = command.opts[0].clone();
-                        let oname = command.opts[1].clone();
-
-                        let connecting = match command.cmd.as_str() {
-                            "con" => true,
-                            "dis" => false,
-                            "tog" | _ => !cfg.read().unwrap().mixer.is_connected(&oname, &iname),
+                match command {
+                    MixerCmd::Connect(switch, iname, oname) => {
+                        let exist = {
+                            let cfg = cfg.read().unwrap();
+                            cfg.mixer.port_exists(true, &oname) && cfg.mixer.port_exists(false, &iname)
                         };
 
-                        // Perform the (dis)connection
-                        cfg.write()
-                            .unwrap()
-                            .mixer
-                            .connect(connecting, &oname, &iname)
-                            .expect("(dis)connecting ports");
+                        if exist {
+                            let connecting =
+                                switch.apply(cfg.read().unwrap().mixer.is_connected(&oname, &iname));
 
-                        if cfg
-                            .read()
-                            .unwrap()
-                            .mixer
-                            .port_exists(true, &oname.to_string())
-                        {
-                            cfg.read().unwrap().mixer.write_info_response(
+                            // Perform the (dis)connection
+                            let _ = cfg.write().unwrap().mixer.connect(connecting, &oname, &iname);
+
+                            config::write_info_response(
+                                &cfg,
                                 true,
-                                &oname.to_string(),
+                                &oname,
                                 &mut stream,
                                 &log,
                             );
                         } else {
                             server::write_response(
                                 &log,
-                                &server::Response {
-                                    ret: 2,
-                                    msg: "Port not found!",
-                                    obj: Value::Null,
-                                },
+                                &server::Response::error(ErrorCode::NotFound, "Port not found!"),
                                 &mut stream,
                             );
                         }
@@ -389,308 +944,551 @@ impl Patchbay {
                         // let _ = stream.flush().log_err(&log);
                         // info!(log, "{}", msg);
                     }
-                    "get" => {
-                        // get all i/os
-                        match command.opts[0].as_str() {
-                            "ports" | "channels" => {
-                                let cfg = cfg.read().unwrap();
-                                // let ref names: Vec<&String> = match is_output {
-                                //     true => &cfg.mixer.outputs,
-                                //     false => &cfg.mixer.inputs,
-                                // }.keys().collect();
-                                let inames: Vec<&String> = cfg.mixer.inputs.keys().collect();
-                                let mut inputs: Vec<Value> = Vec::new();
-                                for iname in inames {
-                                    inputs.push(cfg.mixer.get_port_info(false, iname).unwrap());
+                    MixerCmd::GetChannels => {
+                        server::write_response(
+                            &log,
+                            &server::Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "channels",
+                                obj: cfg.read().unwrap().mixer.get_channels_info(),
+                            },
+                            &mut stream,
+                        );
+                        drop(stream);
+                    }
+                    MixerCmd::GetMonitor => {
+                        let (is_output, name) = {
+                            let monitor = &cfg.read().unwrap().mixer.monitor;
+                            (!monitor.is_input, monitor.channel.clone())
+                        };
+                        config::write_info_response(&cfg, is_output, &name, &mut stream, &log);
+                        drop(stream);
+                    }
+                    MixerCmd::GetSend(iname, oname) => {
+                        let res = cfg.read().unwrap().mixer.get_send_info(&oname, &iname);
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Ok(obj) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "send",
+                                    obj,
+                                },
+                                Err(_) => server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                            },
+                            &mut stream,
+                        );
+                        drop(stream);
+                    }
+                    MixerCmd::GetPort(port) => {
+                        config::write_info_response(
+                            &cfg,
+                            port.is_output,
+                            &port.name,
+                            &mut stream,
+                            &log,
+                        );
+                        drop(stream);
+                    }
+                    MixerCmd::Monitor(filter) => {
+                        // A one-shot subscription, answered by the next
+                        // matching event
+                        info!(log, "Hooking {:?} monitor", filter.property);
+                        cfg.read().unwrap().mixer.events.subscribe(
+                            stream,
+                            filter,
+                            true,
+                            log.clone(),
+                        );
+                    }
+                    MixerCmd::Subscribe(filter) => {
+                        let desc = format!("{:?}", filter);
+                        let sub = stream.try_clone().log_err(&log).map(|s| {
+                            cfg.read().unwrap().mixer.events.subscribe(s, filter, false, log.clone())
+                        });
+                        server::write_response(
+                            &log,
+                            &match sub {
+                                Ok(sub) => {
+                                    info!(log, "New subscription {} ({})", sub, desc);
+                                    server::Response {
+                                        ret: ErrorCode::Ok as i32,
+                                        msg: "subscribed",
+                                        obj: json!({ "sub": sub }),
+                                    }
                                 }
+                                Err(_) => server::Response::error(ErrorCode::Internal, "Could not subscribe!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    MixerCmd::Unsubscribe(id) => {
+                        let res = cfg.read().unwrap().mixer.events.unsubscribe(id, stream.conn());
+                        server::write_response(
+                            &log,
+                            &match res {
+                                Ok(_) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "unsubscribed",
+                                    obj: json!({ "sub": id }),
+                                },
+                                Err(_) => server::Response::error(ErrorCode::NotFound, "Subscription not found!"),
+                            },
+                            &mut stream,
+                        );
+                    }
+                    MixerCmd::SetVolume(port, val) => {
+                        let is_output = port.is_output;
+                        let p_name = port.name;
 
-                                let onames: Vec<&String> = cfg.mixer.outputs.keys().collect();
-                                let mut outputs: Vec<Value> = Vec::new();
-                                for oname in onames {
-                                    outputs.push(cfg.mixer.get_port_info(true, oname).unwrap());
-                                }
+                        let val = {
+                            let cfg = cfg.read().unwrap();
+                            match cfg.mixer.get_fader(is_output, &p_name) {
+                                Ok(cur) => cfg
+                                    .mixer
+                                    .fader_law
+                                    .parse(&val, cur)
+                                    .ok_or((ErrorCode::BadArgs, "Bad value!")),
+                                Err(_) => Err((ErrorCode::NotFound, "Port not found!")),
+                            }
+                        };
+                        let ret = val.and_then(|val| {
+                            cfg.write()
+                                .unwrap()
+                                .mixer
+                                .set_vol(is_output, &p_name, val)
+                                .map_err(|_| (ErrorCode::NotFound, "Port not found!"))
+                        });
 
+                        match ret {
+                            Ok(_) => {
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
+                                    &p_name,
+                                    &mut stream,
+                                    &log,
+                                );
+                            }
+                            Err((ret, msg)) => {
                                 server::write_response(
                                     &log,
-                                    &server::Response {
-                                        ret: 0,
-                                        msg: "channels",
-                                        obj: json!({ "inputs": inputs, "outputs": outputs, }),
-                                    },
+                                    &server::Response::error(ret, msg),
                                     &mut stream,
                                 );
-                                drop(stream);
                             }
-                            "monitor" | "mon" => {
-                                let cfg = cfg.read().unwrap();
-                                cfg.mixer.write_info_response(
-                                    !cfg.mixer.monitor.is_input,
-                                    &cfg.mixer.monitor.channel,
+                        }
+                        drop(stream);
+                    }
+                    MixerCmd::SetBalance(port, val) => {
+                        let is_output = port.is_output;
+                        let p_name = port.name;
+
+                        let ret = cfg
+                            .write()
+                            .unwrap()
+                            .mixer
+                            .set_bal(is_output, &p_name, val);
+
+                        match ret {
+                            Ok(_) => {
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
+                                    &p_name,
                                     &mut stream,
                                     &log,
                                 );
-                                drop(stream);
                             }
-                            _ => {
-                                let ptype = command.opts[0].clone();
-                                let is_output = get_ptype(&ptype);
-                                let p_name = command.opts[1].clone();
-
-                                if cfg
-                                    .read()
-                                    .unwrap()
-                                    .mixer
-                                    .port_exists(is_output, &p_name.to_string())
-                                {
-                                    cfg.read().unwrap().mixer.write_info_response(
-                                        is_output,
-                                        &p_name.to_string(),
-                                        &mut stream,
-                                        &log,
-                                    );
-                                } else {
-                                    server::write_response(
-                                        &log,
-                                        &server::Response {
-                                            ret: 2,
-                                            msg: "Port not found!",
-                                            obj: Value::Null,
-                                        },
-                                        &mut stream,
-                                    );
-                                }
-                                drop(stream);
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                                    &mut stream,
+                                );
                             }
                         }
+                        drop(stream);
+                    }
+                    MixerCmd::SetSend(ref iname, ref oname, _) | MixerCmd::SetSendMute(ref iname, ref oname, _) => {
+                        let send = cfg
+                            .read()
+                            .unwrap()
+                            .mixer
+                            .get_send(oname, iname)
+                            .map_err(|_| (ErrorCode::NotFound, "Port not found!"));
+                        let ret = send.and_then(|send| {
+                            let mut cfg = cfg.write().unwrap();
+                            match command {
+                                MixerCmd::SetSend(_, _, ref val) => cfg
+                                    .mixer
+                                    .fader_law
+                                    .parse(val, send.vol)
+                                    .ok_or((ErrorCode::BadArgs, "Bad value!"))
+                                    .and_then(|vol| {
+                                        cfg.mixer
+                                            .set_send_vol(oname, iname, vol)
+                                            .map_err(|_| (ErrorCode::NotFound, "Port not found!"))
+                                    }),
+                                MixerCmd::SetSendMute(_, _, switch) => cfg
+                                    .mixer
+                                    .set_send_mute(oname, iname, switch.apply(send.mute))
+                                    .map_err(|_| (ErrorCode::NotFound, "Port not found!")),
+                                _ => Ok(()),
+                            }
+                        });
 
-                        // let what = command.opts[0].clone();
-                        // match &*what {
-                        //     "volule"|"vol"|"v"
-                        //     |"balance"|"bal"|"b" => {
-                        //         let ptype = command.opts[1].clone();
-                        //         let is_output = get_ptype(&ptype);
-
-                        //         let p_name = command.opts[2].clone();
-                        //         let val = match &*what {
-                        //             "volule"|"vol"|"v" => cfg.read().unwrap().mixer.get_vol(is_output, &p_name),
-                        //             "balance"|"bal"|"b"|_ => cfg.read().unwrap().mixer.get_bal(is_output, &p_name),
-                        //         };
-                        //         // let vol = cfg.read().unwrap().mixer.get_vol(is_output, &p_name);
-
-                        //         let msg;
-                        //         match val {
-                        //             Ok(v) => { msg = format!("{}", v); },
-                        //             Err(_) => { msg = "Error: port not found!".to_string() },
-                        //         }
-                        //         // let msg = format!("{:?}", vol);
-
-                        //         let _ = stream.write(msg.as_bytes());
-                        //         let _ = stream.write(b"\n");
-                        //         let _ = stream.flush().log_err(&log);
-                        //         info!(log, "{} of {}: `{}`: {}", what, ptype, p_name, msg);
-                        //     }
-                        //     "connections"|"cons"|"con"|"c" => {
-                        //         let ptype = command.opts[1].clone();
-                        //         let is_output = get_ptype(&ptype);
-                        //         let p_name = command.opts[2].clone();
-
-                        //         let cons = cfg.read().unwrap().mixer.get_connected(is_output, &p_name);
-
-                        //         let mut msg;
-                        //         match cons {
-                        //             Ok(c) => {
-                        //                 msg = format!("Currently connected ports for {}: `{}`:",
-                        //                               if is_output {"output"} else {"input"}, p_name);
-                        //                 for p in &c {
-                        //                     msg = format!("{}\n- {}", msg, p);
-                        //                 }
-                        //             }
-                        //             Err(_) => { msg = "Error: port not found!".to_string() },
-                        //         }
-
-                        //         let _ = stream.write(msg.as_bytes());
-                        //         let _ = stream.write(b"\n");
-                        //         let _ = stream.flush().log_err(&log);
-                        //         info!(log, "{}", msg);
-                        //     }
-                        //     _ => {}
-                        // }
+                        let res = cfg.read().unwrap().mixer.get_send_info(oname, iname);
+                        server::write_response(
+                            &log,
+                            &match ret.and(res.map_err(|_| (ErrorCode::NotFound, "Port not found!"))) {
+                                Ok(obj) => server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "send",
+                                    obj,
+                                },
+                                Err((ret, msg)) => server::Response::error(ret, msg),
+                            },
+                            &mut stream,
+                        );
+                        drop(stream);
                     }
-                    "mon" => {
-                        let what = command.opts[0].clone();
-                        match &*what {
-                            "volume" | "vol" | "v" | "connections" | "cons" | "con" | "c"
-                            | "balance" | "bal" | "b" => {
-                                let ptype = command.opts[1].clone();
-                                let is_output = get_ptype(&ptype);
-                                let p_name = command.opts[2].clone();
-                                let h_name = match &*what {
-                                    "volume" | "vol" | "v" => {
-                                        if is_output {
-                                            "output_vol"
-                                        } else {
-                                            "input_vol"
-                                        }
-                                    }
-                                    "connections" | "cons" | "con" | "c" => {
-                                        if is_output {
-                                            "output_con"
-                                        } else {
-                                            "input_con"
-                                        }
-                                    }
-                                    "balance" | "bal" | "b" | _ => {
-                                        if is_output {
-                                            "output_bal"
-                                        } else {
-                                            "input_bal"
-                                        }
-                                    }
-                                }.to_owned();
+                    MixerCmd::SetMute(ref port, switch) | MixerCmd::SetSolo(ref port, switch) => {
+                        let is_output = port.is_output;
+                        let p_name = &port.name;
+                        let is_mute = match command {
+                            MixerCmd::SetMute(..) => true,
+                            _ => false,
+                        };
 
-                                info!(log, "Hooking {} monitor", h_name);
-                                cfg.write().unwrap().mixer.hook(
-                                    h_name,
+                        let cur = if is_mute {
+                            cfg.read().unwrap().mixer.get_mute(is_output, p_name)
+                        } else {
+                            cfg.read().unwrap().mixer.get_solo(is_output, p_name)
+                        };
+                        let ret = cur.and_then(|cur| {
+                            let val = switch.apply(cur);
+                            if is_mute {
+                                cfg.write().unwrap().mixer.set_mute(is_output, p_name, val)
+                            } else {
+                                cfg.write().unwrap().mixer.set_solo(is_output, p_name, val)
+                            }
+                        });
+
+                        match ret {
+                            Ok(_) => {
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
                                     p_name,
-                                    stream,
-                                    log.clone(),
+                                    &mut stream,
+                                    &log,
+                                );
+                            }
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                                    &mut stream,
                                 );
                             }
-                            _ => {}
                         }
+                        drop(stream);
                     }
-                    "set" => {
-                        let what = command.opts[0].clone();
-                        match &*what {
-                            "volule" | "vol" | "v" | "balance" | "bal" | "b" => {
-                                let ptype = command.opts[1].clone();
-                                let is_output = get_ptype(&ptype);
-
-                                let p_name = command.opts[2].clone();
-
-                                let val = if (command.opts[3].chars().nth(0).unwrap() == '+'
-                                    || command.opts[3].chars().nth(0).unwrap() == '-')
-                                    && match &*what {
-                                        "volume" | "vol" | "v" => true,
-                                        _ => false,
-                                    } {
-                                    cfg.read()
-                                        .unwrap()
-                                        .mixer
-                                        .get_vol(is_output, &p_name.clone())
-                                        .unwrap()
-                                        * 100f32
-                                        + command.opts[3].clone().parse::<f32>().unwrap()
-                                } else {
-                                    command.opts[3].clone().parse().unwrap()
-                                };
-                                let ret = match &*what {
-                                    "volule" | "vol" | "v" => cfg.write().unwrap().mixer.set_vol(
-                                        is_output,
-                                        &p_name.clone(),
-                                        val,
-                                    ),
-                                    "balance" | "bal" | "b" | _ => cfg
-                                        .write()
-                                        .unwrap()
-                                        .mixer
-                                        .set_bal(is_output, &p_name.clone(), val),
-                                };
-                                // let vol = cfg.read().unwrap().mixer.get_vol(is_output, &p_name);
-
-                                // let msg;
-                                match ret {
-                                    Ok(_) => {
-                                        cfg.read().unwrap().mixer.write_info_response(
-                                            is_output,
-                                            &p_name.to_string(),
-                                            &mut stream,
-                                            &log,
-                                        );
-                                    }
-                                    Err(_) => {
-                                        server::write_response(
-                                            &log,
-                                            &server::Response {
-                                                ret: 2,
-                                                msg: "Port not found!",
-                                                obj: Value::Null,
-                                            },
-                                            &mut stream,
-                                        );
-                                        // msg = "Error: port not found!".to_string()
-                                    }
-                                }
-                                drop(stream);
-                                // let msg = format!("{:?}", vol);
+                    MixerCmd::SetMonitor(port) => {
+                        let is_output = port.is_output;
+                        let p_name = port.name;
 
-                                // let _ = stream.write(msg.as_bytes());
-                                // let _ = stream.write(b"\n");
-                                // let _ = stream.flush().log_err(&log);
-                                // info!(log, "{}", msg);
+                        let res = cfg.write().unwrap().mixer.set_mon(is_output, &p_name);
+                        match res {
+                            Ok(_) => {
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
+                                    &p_name,
+                                    &mut stream,
+                                    &log,
+                                );
                             }
-                            "monitor" | "mon" | "m" => {
-                                let ptype = command.opts[1].clone();
-                                let is_output = get_ptype(&ptype);
-
-                                let p_name = command.opts[2].clone();
-                                let res = cfg.write().unwrap().mixer.set_mon(is_output, &p_name);
-                                match res {
-                                    Ok(_) => {
-                                        cfg.read().unwrap().mixer.write_info_response(
-                                            is_output,
-                                            &p_name.to_string(),
-                                            &mut stream,
-                                            &log,
-                                        );
-                                    }
-                                    Err(_) => {
-                                        server::write_response(
-                                            &log,
-                                            &server::Response {
-                                                ret: 2,
-                                                msg: "Port not found!",
-                                                obj: Value::Null,
-                                            },
-                                            &mut stream,
-                                        );
-                                    }
-                                }
-                                drop(stream);
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                                    &mut stream,
+                                );
                             }
-                            _ => {}
                         }
+                        drop(stream);
                     }
-                    /*
-                    "mkp" => {
-                    }
-                    "rmp" => {
-                    }
-
-                     */
-                    _ => {
+                    MixerCmd::Meters => {
                         server::write_response(
                             &log,
                             &server::Response {
-                                ret: 1,
-                                msg: "Bad command!",
-                                obj: Value::Null,
+                                ret: ErrorCode::Ok as i32,
+                                msg: "meters",
+                                obj: meters.read().unwrap().get_info(),
                             },
                             &mut stream,
                         );
                         drop(stream);
-                        // let msg = format!("Bad command: `{}`", command.cmd);
-                        // let _ = stream.write(msg.as_bytes());
-                        // let _ = stream.write(b"\n");
-                        // let _ = stream.flush().log_err(&log);
-                        // error!(log, "{}", msg);
                     }
+                    MixerCmd::StreamMeters(rate) => {
+                        let rate = rate
+                            .unwrap_or(DEFAULT_METER_RATE)
+                            .max(MIN_METER_RATE)
+                            .min(MAX_METER_RATE);
+                        let period = std::time::Duration::from_millis((1000.0 / rate) as u64);
+
+                        info!(log, "Streaming meters at {} Hz", rate);
+                        let meters = meters.clone();
+                        let log = log.clone();
+                        thread::spawn(move || loop {
+                            let res = server::try_write_response(
+                                &log,
+                                &server::Response {
+                                    ret: ErrorCode::Ok as i32,
+                                    msg: "meters",
+                                    obj: meters.read().unwrap().get_info(),
+                                },
+                                &mut stream,
+                            );
+                            if res.is_err() {
+                                info!(log, "Meter stream closed");
+                                break;
+                            }
+                            thread::sleep(period);
+                        });
+                    }
+                    MixerCmd::CreatePort(port, mono) => {
+                        let is_output = port.is_output;
+                        let p_name = port.name;
+
+                        match channels.create(is_output, &p_name, mono) {
+                            Ok(_) => {
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
+                                    &p_name,
+                                    &mut stream,
+                                    &log,
+                                );
+                            }
+                            Err((ret, msg)) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ret, msg),
+                                    &mut stream,
+                                );
+                            }
+                        }
+                        drop(stream);
+                    }
+                    MixerCmd::RemovePort(port) => {
+                        let is_output = port.is_output;
+                        let p_name = port.name;
+
+                        match channels.remove(is_output, &p_name) {
+                            Ok(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response {
+                                        ret: ErrorCode::Ok as i32,
+                                        msg: "port removed",
+                                        obj: json!({
+                                            "port":  &p_name,
+                                            "ptype": if is_output {"out"} else {"in"},
+                                        }),
+                                    },
+                                    &mut stream,
+                                );
+                            }
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::NotFound, "Port not found!"),
+                                    &mut stream,
+                                );
+                            }
+                        }
+                        drop(stream);
+                    }
+                    MixerCmd::RenamePort(port, new_name) => {
+                        let is_output = port.is_output;
+                        let ptype = if is_output { "out" } else { "in" };
+                        let p_name = port.name;
+
+                        let ret = {
+                            let cfg = cfg.read().unwrap();
+                            if !cfg.mixer.port_exists(is_output, &p_name) {
+                                Err((ErrorCode::NotFound, "Port not found!"))
+                            } else if cfg.mixer.port_exists(is_output, &new_name) {
+                                Err((ErrorCode::Failed, "Port already exists!"))
+                            } else {
+                                Ok(())
+                            }
+                        }.and_then(|_| {
+                            // The JACK ports can only be renamed once the
+                            // process callback let go of them, and all of
+                            // them are reclaimed before any is renamed so
+                            // that a busy one leaves the channel as it was
+                            let taken = ports.lock().unwrap().take(is_output, &p_name);
+                            publish();
+
+                            let reclaimed: Vec<Result<Port, Arc<Port>>> =
+                                taken.into_iter().map(|port| Port::reclaim(port, &mix)).collect();
+                            let res = if reclaimed.iter().any(|port| port.is_err()) {
+                                let taken = reclaimed
+                                    .into_iter()
+                                    .map(|port| port.map(Arc::new).unwrap_or_else(|port| port))
+                                    .collect();
+                                ports.lock().unwrap().put(is_output, &p_name, taken);
+                                Err((ErrorCode::Failed, "Port still in use!"))
+                            } else {
+                                let mut reclaimed: Vec<Port> =
+                                    reclaimed.into_iter().filter_map(|port| port.ok()).collect();
+                                let res = rename_channel_ports(&mut reclaimed, &p_name, &new_name, &cfg, &log);
+                                let renamed = reclaimed.into_iter().map(Arc::new).collect();
+                                if res.is_ok() {
+                                    cfg.write()
+                                        .unwrap()
+                                        .mixer
+                                        .rename_port(is_output, &p_name, &new_name)
+                                        .expect("renaming checked port");
+                                    ports.lock().unwrap().put(is_output, &new_name, renamed);
+                                    Ok(())
+                                } else {
+                                    ports.lock().unwrap().put(is_output, &p_name, renamed);
+                                    Err((ErrorCode::Failed, "Could not rename port!"))
+                                }
+                            };
+                            update_meters();
+                            publish();
+                            res
+                        });
+
+                        match ret {
+                            Ok(_) => {
+                                info!(log, "Renamed {} port `{}` to `{}`", ptype, p_name, new_name);
+                                config::write_info_response(
+                                    &cfg,
+                                    is_output,
+                                    &new_name,
+                                    &mut stream,
+                                    &log,
+                                );
+                            }
+                            Err((ret, msg)) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ret, msg),
+                                    &mut stream,
+                                );
+                            }
+                        }
+                        drop(stream);
+                    }
+                    MixerCmd::Save(path) => {
+                        let path = path.as_ref().map(Path::new);
+                        match cfg.write().unwrap().save(path, &log) {
+                            Ok(path) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response {
+                                        ret: ErrorCode::Ok as i32,
+                                        msg: "config saved",
+                                        obj: json!({ "path": path }),
+                                    },
+                                    &mut stream,
+                                );
+                            }
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::Failed, "Could not save config!"),
+                                    &mut stream,
+                                );
+                            }
+                        }
+                        drop(stream);
+                    }
+                    MixerCmd::Load(path) => {
+                        let path = path
+                            .map(|p| Path::new(&p).to_owned())
+                            .or_else(|| cfg.read().unwrap().path.clone());
+
+                        match path.ok_or(()).and_then(|path| config::load(&path, &log)) {
+                            Ok(new) => {
+                                // Channels that are gone or switched between
+                                // mono and stereo get new JACK ports
+                                let (removed, added) = {
+                                    let old = &cfg.read().unwrap().mixer;
+                                    (changed_ports(old, &new.mixer), changed_ports(&new.mixer, old))
+                                };
+                                cfg.write().unwrap().replace(new);
+
+                                let mut taken = Vec::new();
+                                for &(is_output, ref name, _) in &removed {
+                                    taken.extend(ports.lock().unwrap().take(is_output, name));
+                                }
+                                publish();
+                                for port in taken {
+                                    match Port::reclaim(port, &mix) {
+                                        Ok(port) => {
+                                            let _ = port.unregister(&cli.lock().unwrap()).log_err(&log);
+                                        }
+                                        Err(_) => {
+                                            error!(log, "Port still in use, leaving it registered");
+                                        }
+                                    }
+                                }
+                                {
+                                    let jcli = cli.lock().unwrap();
+                                    let mut ports = ports.lock().unwrap();
+                                    for &(is_output, ref name, mono) in &added {
+                                        let _ = ports.add(is_output, name, mono, &jcli).log_err(&log);
+                                    }
+                                }
+                                update_meters();
+                                publish();
+
+                                if let Some(ref t_jacon) = t_jacon {
+                                    let _ = t_jacon.send(jacon::Signals::DisconnectAll).log_err(&log);
+                                    let _ = t_jacon.send(jacon::Signals::ReconnectGood).log_err(&log);
+                                }
+
+                                let cfg = cfg.read().unwrap();
+                                info!(log, "Loaded config from {:?}", cfg.path);
+                                server::write_response(
+                                    &log,
+                                    &server::Response {
+                                        ret: ErrorCode::Ok as i32,
+                                        msg: "config loaded",
+                                        obj: json!({ "path": cfg.path }),
+                                    },
+                                    &mut stream,
+                                );
+                            }
+                            Err(_) => {
+                                server::write_response(
+                                    &log,
+                                    &server::Response::error(ErrorCode::Failed, "Could not load config!"),
+                                    &mut stream,
+                                );
+                            }
+                        }
+                        drop(stream);
+                    }
+                }
+
+                // Hand the updated mixer over to the process callback
+                if changes_mix {
+                    publish();
                 }
             }
         }));
     }
 
-    pub fn get_cmd_sender(&self) -> Option<&Sender<(TcpStream, server::Command)>> {
+    pub fn get_cmd_sender(&self) -> Option<&Sender<(server::Stream, server::Command)>> {
         return self.t_cmd.as_ref();
     }
 }
@@ -1,4 +1,5 @@
 extern crate jack;
+extern crate jack_sys;
 #[macro_use]
 extern crate slog;
 extern crate libc;
@@ -136,6 +137,8 @@ impl j::NotificationHandler for Notifications {
 pub struct Process {
     // TODO: use this logger!
     log: slog::Logger,
+    // Only ever holds `CB::process` hooks, so that the realtime thread never
+    // contends with the notification thread for this lock
     hooks: Arc<Mutex<Vec<CB>>>,
 }
 
@@ -196,6 +199,7 @@ impl AnyClient {
 pub trait JackClientUtils {
     fn connect_ports_by_name_if(&self, &bool, &str, &str) -> Result<(), j::Error>;
     fn port_name_by_id(&self, j::PortId) -> Option<String>;
+    fn port_connections(&self, port_name: &str) -> Option<Vec<String>>;
 }
 
 impl JackClientUtils for j::Client {
@@ -218,6 +222,45 @@ impl JackClientUtils for j::Client {
             None => None,
         }
     }
+
+    /// Names of the ports a port is connected to, or `None` if there is no
+    /// such port
+    fn port_connections(&self, port_name: &str) -> Option<Vec<String>> {
+        let port = self.port_by_name(port_name)?;
+        if port.connected_count().ok()? == 0 {
+            return Some(Vec::new());
+        }
+
+        let other_side = if port.flags().contains(j::PortFlags::IS_INPUT) {
+            j::PortFlags::IS_OUTPUT
+        } else {
+            j::PortFlags::IS_INPUT
+        };
+        Some(
+            self.ports(None, None, other_side)
+                .into_iter()
+                .filter(|p| port.is_connected_to(p).unwrap_or(false))
+                .collect(),
+        )
+    }
+}
+
+pub trait JackPortUtils {
+    fn latency_range(&self, mode: j::LatencyType) -> (j::Frames, j::Frames);
+}
+
+impl<PS> JackPortUtils for j::Port<PS> {
+    /// Minimum and maximum latency (in frames) of a port, in the capture or
+    /// playback direction
+    fn latency_range(&self, mode: j::LatencyType) -> (j::Frames, j::Frames) {
+        let mode = match mode {
+            j::LatencyType::Capture => jack_sys::JackCaptureLatency,
+            j::LatencyType::Playback => jack_sys::JackPlaybackLatency,
+        };
+        let mut range = jack_sys::jack_latency_range_t::default();
+        unsafe { jack_sys::jack_port_get_latency_range(self.raw(), mode, &mut range) };
+        (range.min, range.max)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -256,12 +299,15 @@ unsafe impl j::PortSpec for AnySpec {
 }
 
 pub struct AnyAudioOutPort<'a> {
-    _port: &'a mut j::Port<AnySpec>,
+    _port: &'a j::Port<AnySpec>,
     buffer: &'a mut [f32],
 }
 
 impl<'a> AnyAudioOutPort<'a> {
-    pub fn new(port: &'a mut j::Port<AnySpec>, ps: &'a j::ProcessScope) -> Self {
+    /// The port is only borrowed immutably so that ports can be shared with
+    /// the process callback; it is up to the caller not to hold two of these
+    /// for the same port at once.
+    pub fn new(port: &'a j::Port<AnySpec>, ps: &'a j::ProcessScope) -> Self {
         assert_eq!(port.client_ptr(), ps.client_ptr());
         let buff = unsafe {
             std::slice::from_raw_parts_mut(
@@ -349,6 +395,7 @@ pub struct Client {
     pub process_handler: Arc<Mutex<Option<Process>>>,
     do_recon: Arc<Mutex<bool>>,
     hooks: Arc<Mutex<Vec<CB>>>,
+    proc_hooks: Arc<Mutex<Vec<CB>>>,
 }
 
 impl Client {
@@ -356,6 +403,7 @@ impl Client {
         let noti_logger = logger.new(o!());
         let proc_logger = logger.new(o!());
         let hooks = Arc::new(Mutex::new(Vec::new()));
+        let proc_hooks = Arc::new(Mutex::new(Vec::new()));
         Client {
             jclient: Arc::new(Mutex::new(AnyClient::None)),
             name: name.to_string(),
@@ -364,9 +412,13 @@ impl Client {
                 noti_logger,
                 hooks.clone(),
             )))),
-            process_handler: Arc::new(Mutex::new(Some(Process::new(proc_logger, hooks.clone())))),
+            process_handler: Arc::new(Mutex::new(Some(Process::new(
+                proc_logger,
+                proc_hooks.clone(),
+            )))),
             do_recon: Arc::new(Mutex::new(false)),
             hooks: hooks.clone(),
+            proc_hooks,
         }
     }
 
@@ -387,6 +439,7 @@ impl Client {
         let not_han = self.notifications_handler.clone();
         let proc_han = self.process_handler.clone();
         let hooks = self.hooks.clone();
+        let proc_hooks = self.proc_hooks.clone();
         self.notifications_handler
             .lock()?
             .as_mut()
@@ -403,7 +456,7 @@ impl Client {
                 // Still "recover" the notifications_handler
                 // TODO: actually give the proper loggers to the new handlers here
                 *not_han.lock().unwrap() = Some(Notifications::new(logger.clone(), hooks.clone()));
-                *proc_han.lock().unwrap() = Some(Process::new(logger.clone(), hooks.clone()));
+                *proc_han.lock().unwrap() = Some(Process::new(logger.clone(), proc_hooks.clone()));
             })));
 
         Ok(())
@@ -505,11 +558,15 @@ impl Client {
     }
 
     pub fn hook(&mut self, cb: CB) {
-        self.notifications_handler
-            .lock()
-            .unwrap()
-            .as_mut()
-            .unwrap()
-            .hook(cb);
+        match cb {
+            CB::process(_) => self.proc_hooks.lock().unwrap().push(cb),
+            _ => self
+                .notifications_handler
+                .lock()
+                .unwrap()
+                .as_mut()
+                .unwrap()
+                .hook(cb),
+        }
     }
 }
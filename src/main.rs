@@ -16,16 +16,26 @@ extern crate jam;
 
 use std::io;
 use std::io::BufRead;
+use std::sync::mpsc::channel;
 use std::sync::{Arc, RwLock};
+use std::thread;
 
 use slog::Drain;
 
+mod command;
 mod config;
+mod daemon;
+mod events;
 mod jacon;
 mod jamyxer;
+mod meter;
+mod patterns;
+mod rtcell;
 mod server;
 mod utils;
 
+use utils::LogError;
+
 fn setup_log(verbosity: slog::Level) -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().stderr().build();
     let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -36,12 +46,21 @@ fn setup_log(verbosity: slog::Level) -> slog::Logger {
 }
 
 fn main() {
-    static VERSION: &str = env!("CARGO_PKG_VERSION");
     let cargs = clap_app!(Jamyx =>
-        (version: VERSION)
+        (version: daemon::VERSION)
         (author: "Javier A. Pollak")
         (about: "Jackaudio mixer/patchbay suite written in rust")
         (@arg config: -c --config +takes_value "Sets custom config file path")
+        (@arg socket: -s --socket +takes_value "Sets custom Unix socket path (empty to disable)")
+        (@arg listen: -l --listen +takes_value +multiple number_of_values(1)
+            "Sets a TCP address to listen on (ADDR:PORT, ADDR or PORT), can be repeated")
+        (@arg no_tcp: --("no-tcp") conflicts_with[listen] "Disables the TCP listeners")
+        (@arg capture: --capture +takes_value
+            "Writes the current JACK connections to a patchbay profile and exits")
+        (@arg capture_client: --("capture-client") +takes_value +multiple number_of_values(1) requires[capture]
+            "Only captures the connections of a client (name or pattern), can be repeated")
+        (@arg generalize: --generalize requires[capture]
+            "Turns PIDs in the client names of captured ports into wildcards")
         (@arg verbosity: -v ... "Sets custom verbosity level")
     ).get_matches();
 
@@ -66,16 +85,42 @@ fn main() {
     let log = log.new(o!("version" => "0.0.1"));
     info!(log, "Logger init");
 
+    // Checked before anything, as a bad pattern would capture every client.
+    // Errors go straight to stderr, the async logger isn't flushed on exit
+    let mut capture_clients = Vec::new();
+    for client in cargs.values_of("capture_client").into_iter().flat_map(|c| c) {
+        match patterns::Pattern::parse(client) {
+            Ok(pattern) => capture_clients.push(pattern),
+            Err(e) => {
+                eprintln!("Bad client pattern `{}`: {}", client, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Init JClient
+    let mut jclient = jam::Client::new("Jacon", log.new(o!()));
+    jclient.init(None).unwrap();
+
+    if let Some(path) = cargs.value_of("capture") {
+        let rules = {
+            let cli = jclient.jclient.lock().unwrap();
+            let cli = cli.as_inactive().expect("getting jack client");
+            jacon::capture_graph(cli, &capture_clients, cargs.is_present("generalize"))
+        };
+        if config::save_profile(std::path::Path::new(path), &rules, &log).is_err() {
+            eprintln!("Could not write the profile to {}", path);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Parse config
     let config = Arc::new(RwLock::new(config::parse(
         cargs.value_of("config").unwrap_or("config.json"),
         log.new(o!()),
     )));
 
-    // Init JClient
-    let mut jclient = jam::Client::new("Jacon", log.new(o!()));
-    jclient.init(None).unwrap();
-
     // Set callbacks
     let cblog = log.new(o!());
     jclient.hook(jam::CB::client_registration(Box::new(move |_, cn, of| {
@@ -98,6 +143,7 @@ fn main() {
         log.clone(), jclient.jclient.clone(), config.clone());
 
     jamyxer.init(&mut jclient);
+    jamyxer.set_jacon(jacon.get_sig_sender().expect("getting jacon signal sender").clone());
 
     // =========== START ===========
     // Activate JClient
@@ -112,13 +158,35 @@ fn main() {
     debug!(log, "Starting Jamyxer...");
     jamyxer.start();
 
+    // Setup the daemon-wide commands
+    let (t_quit, r_quit) = channel();
+    let mut daemon = daemon::Daemon::new(
+        log.clone(), jclient.jclient.clone(), config.clone(), jacon.get_failures());
+
+    debug!(log, "Starting daemon...");
+    daemon.start(
+        jamyxer.get_cmd_sender().expect("getting jamyxer cmd sender").clone(),
+        t_quit.clone(),
+    );
+
     let sender = server::CmdSender::new(
         jamyxer.get_cmd_sender().expect("getting jamyxer cmd sender").clone(),
         jacon.  get_cmd_sender().expect("getting jacon cmd sender").clone(),
-        jamyxer.get_cmd_sender().expect("getting jamyxer cmd sender").clone(),
+        daemon. get_cmd_sender().expect("getting daemon cmd sender").clone(),
     );
     debug!(log, "Starting server...");
-    server::start(log.clone(), sender);
+    // Command line options take precedence over the config file
+    let mut server_cfg = config.read().unwrap().server.clone();
+    if let Some(path) = cargs.value_of("socket") {
+        server_cfg.socket = Some(path.to_owned());
+    }
+    if let Some(addrs) = cargs.values_of("listen") {
+        server_cfg.listen = addrs.map(|a| a.to_owned()).collect();
+    }
+    if cargs.is_present("no_tcp") {
+        server_cfg.listen.clear();
+    }
+    let socket = server::start(log.clone(), sender, &server_cfg);
 
     debug!(log, "Done Activation phase!");
     /*
@@ -131,10 +199,19 @@ fn main() {
     }
     */
 
-    let mut user_input = String::new();
-    io::stdin().read_line(&mut user_input).expect("waiting for user input");
+    // Run until a line on stdin or a `shutdown` command
+    thread::spawn(move || {
+        let mut user_input = String::new();
+        io::stdin().read_line(&mut user_input).expect("waiting for user input");
+        let _ = t_quit.send(());
+    });
+    let _ = r_quit.recv();
 
     // Deactivate JClient
     jclient.deactivate().unwrap();
     info!(log, "Jclient deactivate");
+
+    if let Some(path) = socket {
+        let _ = std::fs::remove_file(path).log_err(&log);
+    }
 }
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+/// Peak falloff of the meters in dB per second
+const PEAK_FALLOFF_DB: f32 = 20.0;
+/// Integration time of the RMS meters in seconds
+const RMS_WINDOW: f32 = 0.3;
+
+/// Level meter for a single audio channel.
+///
+/// Written from the process callback and read from any other thread. The
+/// values are stored as the bit patterns of `f32`s in atomics so that
+/// neither side ever has to take a lock.
+pub struct Meter {
+    peak: AtomicU32,
+    ms: AtomicU32,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Meter {
+            peak: AtomicU32::new(0),
+            ms: AtomicU32::new(0),
+        }
+    }
+
+    /// Feed one period worth of samples to the meter.
+    ///
+    /// `ballistics` comes from `Meter::ballistics` and only has to be
+    /// computed once per period.
+    pub fn update(&self, buf: &[f32], ballistics: (f32, f32)) {
+        let (falloff, coeff) = ballistics;
+
+        let mut peak = 0.0f32;
+        let mut sum = 0.0f32;
+        for s in buf {
+            peak = peak.max(s.abs());
+            sum += s * s;
+        }
+        let ms = if buf.is_empty() { 0.0 } else { sum / buf.len() as f32 };
+
+        let old_peak = f32::from_bits(self.peak.load(Ordering::Relaxed));
+        let old_ms = f32::from_bits(self.ms.load(Ordering::Relaxed));
+
+        self.peak
+            .store(peak.max(old_peak * falloff).to_bits(), Ordering::Relaxed);
+        self.ms
+            .store((old_ms + coeff * (ms - old_ms)).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Peak falloff factor and RMS smoothing coefficient for a period of
+    /// `n_frames` at `sample_rate`
+    pub fn ballistics(n_frames: u32, sample_rate: usize) -> (f32, f32) {
+        let dt = n_frames as f32 / sample_rate.max(1) as f32;
+        let falloff = 10f32.powf(-PEAK_FALLOFF_DB * dt / 20.0);
+        let coeff = 1.0 - (-dt / RMS_WINDOW).exp();
+        (falloff, coeff)
+    }
+
+    pub fn peak(&self) -> f32 {
+        f32::from_bits(self.peak.load(Ordering::Relaxed))
+    }
+
+    pub fn rms(&self) -> f32 {
+        f32::from_bits(self.ms.load(Ordering::Relaxed)).sqrt()
+    }
+
+    pub fn get_info(&self) -> Value {
+        json!({
+            "peak": self.peak(),
+            "rms":  self.rms(),
+        })
+    }
+}
+
+/// Meters of every channel side (`L`, `R` or `M`) of a mixer port
+pub type PortMeters = HashMap<String, Arc<Meter>>;
+
+/// Registry of all the meters of the mixer.
+///
+/// The process callback only ever touches the `Meter`s through the `Arc`s
+/// held by the ports themselves; this is what the command thread reads.
+pub struct MeterBank {
+    pub inputs: HashMap<String, PortMeters>,
+    pub outputs: HashMap<String, PortMeters>,
+    pub monitor: PortMeters,
+}
+
+impl MeterBank {
+    pub fn new() -> Self {
+        MeterBank {
+            inputs: HashMap::new(),
+            outputs: HashMap::new(),
+            monitor: HashMap::new(),
+        }
+    }
+
+    fn port_info(meters: &PortMeters) -> Value {
+        let mut obj = serde_json::Map::new();
+        for (side, meter) in meters {
+            obj.insert(side.clone(), meter.get_info());
+        }
+        Value::Object(obj)
+    }
+
+    pub fn get_info(&self) -> Value {
+        let mut inputs = serde_json::Map::new();
+        for (name, meters) in &self.inputs {
+            inputs.insert(name.clone(), Self::port_info(meters));
+        }
+        let mut outputs = serde_json::Map::new();
+        for (name, meters) in &self.outputs {
+            outputs.insert(name.clone(), Self::port_info(meters));
+        }
+
+        json!({
+            "inputs":  inputs,
+            "outputs": outputs,
+            "monitor": Self::port_info(&self.monitor),
+        })
+    }
+}
@@ -0,0 +1,604 @@
+extern crate regex;
+
+use std;
+use std::collections::{HashMap, HashSet};
+
+use self::regex::Regex;
+
+/// A port name in a patchbay rule:
+/// - `/REGEX/`: a regex the whole name has to match
+/// - a name with `*` (any text) or `?` (any character): a glob, where every
+///   wildcard is a capture group
+/// - anything else: the exact name of a port
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// What a pattern captured from a port name; group 0 is the whole name
+pub struct Captures {
+    groups: Vec<Option<String>>,
+    names: HashMap<String, String>,
+}
+
+impl Captures {
+    fn get(&self, group: &str) -> Option<&str> {
+        match group.parse::<usize>() {
+            Ok(i) => self.groups.get(i).and_then(|g| g.as_ref()).map(|g| g.as_str()),
+            Err(_) => self.names.get(group).map(|g| g.as_str()),
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str("(.*)"),
+            '?' => re.push_str("(.)"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+fn is_regex(s: &str) -> bool {
+    s.len() > 1 && s.starts_with('/') && s.ends_with('/')
+}
+
+fn is_glob(s: &str) -> bool {
+    s.contains('*') || s.contains('?')
+}
+
+impl Pattern {
+    pub fn parse(s: &str) -> Result<Self, regex::Error> {
+        if is_regex(s) {
+            Ok(Pattern::Regex(Regex::new(&format!("^(?:{})$", &s[1..s.len() - 1]))?))
+        } else if is_glob(s) {
+            Ok(Pattern::Regex(Regex::new(&glob_to_regex(s))?))
+        } else {
+            Ok(Pattern::Exact(s.to_owned()))
+        }
+    }
+
+    /// Whether a rule side is more than a plain port name
+    pub fn is_pattern(s: &str) -> bool {
+        is_regex(s) || is_glob(s)
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        match *self {
+            Pattern::Exact(ref n) => n == name,
+            Pattern::Regex(ref re) => re.is_match(name),
+        }
+    }
+
+    pub fn captures(&self, name: &str) -> Option<Captures> {
+        match *self {
+            Pattern::Exact(ref n) => if n == name {
+                Some(Captures {
+                    groups: vec![Some(name.to_owned())],
+                    names: HashMap::new(),
+                })
+            } else {
+                None
+            },
+            Pattern::Regex(ref re) => {
+                let caps = re.captures(name)?;
+                Some(Captures {
+                    groups: caps.iter().map(|g| g.map(|g| g.as_str().to_owned())).collect(),
+                    names: re.capture_names()
+                        .filter_map(|n| n)
+                        .filter_map(|n| caps.name(n).map(|g| (n.to_owned(), g.as_str().to_owned())))
+                        .collect(),
+                })
+            }
+        }
+    }
+}
+
+/// Length of what a pattern matches literally before its first wildcard;
+/// a regex has no literal prefix
+fn literal_prefix(s: &str) -> usize {
+    if is_regex(s) {
+        0
+    } else {
+        s.chars().take_while(|&c| c != '*' && c != '?').count()
+    }
+}
+
+/// The patterns among the keys of a map, compiled once and ordered by
+/// precedence: the longest literal prefix first, ties going to the pattern
+/// that sorts first. Plain names and bad patterns are left out
+#[derive(Clone, Debug, Default)]
+pub struct Ranked(Vec<(String, Pattern)>);
+
+impl Ranked {
+    pub fn new<'a, I: IntoIterator<Item = &'a String>>(keys: I) -> Self {
+        let mut ranked: Vec<(String, Pattern)> = keys
+            .into_iter()
+            .filter(|k| Pattern::is_pattern(k))
+            .filter_map(|k| Pattern::parse(k).ok().map(|p| (k.clone(), p)))
+            .collect();
+        ranked.sort_by(|a, b| {
+            literal_prefix(&b.0)
+                .cmp(&literal_prefix(&a.0))
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        Ranked(ranked)
+    }
+
+    /// The first pattern, by precedence, that any of the names matches
+    pub fn find(&self, names: &[&str]) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|&&(_, ref p)| names.iter().any(|n| p.is_match(n)))
+            .map(|&(ref k, _)| k.as_str())
+    }
+}
+
+/// The source side of a rule that only matches `name`, even if it looks
+/// like a pattern
+pub fn exact(name: &str) -> String {
+    if Pattern::is_pattern(name) {
+        format!("/{}/", regex::escape(name))
+    } else {
+        name.to_owned()
+    }
+}
+
+/// The destination side of a rule that stands for `name`, with no capture
+/// references in it
+pub fn escape_refs(name: &str) -> String {
+    name.replace('$', "$$")
+}
+
+/// Fill in `$N`, `${N}` and `${NAME}` in the destination side of a rule with
+/// what the source side captured (`$$` is a plain `$`). Captures are escaped
+/// when they end up in a regex
+pub fn expand(template: &str, caps: &Captures) -> String {
+    let escape = is_regex(template);
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let mut group = String::new();
+        match chars.peek().cloned() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+                continue;
+            }
+            Some('{') => {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    if c == '}' {
+                        break;
+                    }
+                    group.push(c);
+                }
+            }
+            _ => while let Some(c) = chars.peek().cloned() {
+                if !c.is_digit(10) {
+                    break;
+                }
+                group.push(c);
+                chars.next();
+            },
+        }
+
+        if group.is_empty() {
+            // Not a reference, e.g. the end anchor of a regex
+            out.push('$');
+        } else if let Some(text) = caps.get(&group) {
+            out.push_str(&if escape { regex::escape(text) } else { text.to_owned() });
+        }
+    }
+    out
+}
+
+/// The input side of a rule, compiled unless it refers to what the output
+/// side captured
+#[derive(Clone, Debug)]
+enum Dest {
+    Compiled(Pattern),
+    Template(String),
+}
+
+/// Rules in the form of the config file's `connections`, compiled once.
+/// Rules that aren't valid patterns never match
+#[derive(Clone, Debug, Default)]
+pub struct Rules(Vec<(Pattern, Vec<Dest>)>);
+
+impl Rules {
+    pub fn new(rules: &HashMap<String, HashSet<String>>) -> Self {
+        Rules(
+            rules
+                .iter()
+                .filter_map(|(oo, iis)| {
+                    let dests = iis
+                        .iter()
+                        .filter_map(|ii| {
+                            if ii.contains('$') {
+                                Some(Dest::Template(ii.clone()))
+                            } else {
+                                Pattern::parse(ii).ok().map(Dest::Compiled)
+                            }
+                        }).collect();
+                    Pattern::parse(oo).ok().map(|o| (o, dests))
+                }).collect(),
+        )
+    }
+
+    /// Whether the rules have the output port `oname` connected to the input
+    /// port `iname`
+    pub fn wants(&self, oname: &str, iname: &str) -> bool {
+        self.0.iter().any(|&(ref oo, ref iis)| match oo.captures(oname) {
+            Some(caps) => iis.iter().any(|ii| match *ii {
+                Dest::Compiled(ref i) => i.is_match(iname),
+                Dest::Template(ref ii) => Pattern::parse(&expand(ii, &caps))
+                    .map(|i| i.is_match(iname))
+                    .unwrap_or(false),
+            }),
+            None => false,
+        })
+    }
+}
+
+/// The `(output, input)` port pairs a rule connects among the given ports.
+/// Exact names are taken as they are, even if they aren't in the lists
+pub fn rule_pairs(
+    oo: &str,
+    iis: &HashSet<String>,
+    outputs: &[String],
+    inputs: &[String],
+) -> Result<Vec<(String, String)>, regex::Error> {
+    let opat = Pattern::parse(oo)?;
+    let outs: Vec<(String, Captures)> = match opat {
+        Pattern::Exact(ref name) => opat.captures(name).map(|c| (name.clone(), c)).into_iter().collect(),
+        Pattern::Regex(_) => outputs
+            .iter()
+            .filter_map(|o| opat.captures(o).map(|c| (o.clone(), c)))
+            .collect(),
+    };
+
+    let mut pairs = Vec::new();
+    for (oname, caps) in outs {
+        for ii in iis {
+            match Pattern::parse(&expand(ii, &caps))? {
+                Pattern::Exact(iname) => pairs.push((oname.clone(), iname)),
+                ipat => for iname in inputs.iter().filter(|i| ipat.is_match(i)) {
+                    pairs.push((oname.clone(), iname.clone()));
+                },
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// The input ports of a client rule destination: every input of a client
+/// (`CLIENT`), or the ports of a mixer channel (`CLIENT:CHANNEL`, for
+/// `CLIENT:CHANNEL L` and `CLIENT:CHANNEL R` or `CLIENT:CHANNEL M`)
+fn dest_ports<'a>(dest: &str, inputs: &'a [String]) -> Vec<&'a String> {
+    inputs
+        .iter()
+        .filter(|i| {
+            if !i.starts_with(dest) {
+                return false;
+            }
+            let rest = &i[dest.len()..];
+            if dest.contains(':') {
+                rest.is_empty() || (rest.starts_with(' ') && !rest[1..].contains(' '))
+            } else {
+                rest.starts_with(':')
+            }
+        }).collect()
+}
+
+/// The `(output, input)` port pairs a client rule connects: the outputs of
+/// every client whose name matches `src` go to each destination in order,
+/// the Nth output to the Nth input. When the counts differ the shorter side
+/// wraps around, so a mono client feeds both sides of a stereo channel and
+/// a stereo client is mixed into a mono one
+pub fn client_pairs(
+    src: &str,
+    dsts: &HashSet<String>,
+    outputs: &[String],
+    inputs: &[String],
+) -> Result<Vec<(String, String)>, regex::Error> {
+    let pat = Pattern::parse(src)?;
+
+    // Matching clients, in the order their first port shows up
+    let mut clients: Vec<(String, Captures, Vec<&String>)> = Vec::new();
+    for oname in outputs {
+        let client = oname.split(':').next().unwrap_or("");
+        if let Some(&mut (_, _, ref mut ports)) = clients.iter_mut().find(|c| c.0 == client) {
+            ports.push(oname);
+            continue;
+        }
+        if let Some(caps) = pat.captures(client) {
+            clients.push((client.to_owned(), caps, vec![oname]));
+        }
+    }
+
+    let mut pairs = Vec::new();
+    for (_, caps, srcs) in clients {
+        for dst in dsts {
+            let dsts = dest_ports(&expand(dst, &caps), inputs);
+            if dsts.is_empty() {
+                continue;
+            }
+            for k in 0..std::cmp::max(srcs.len(), dsts.len()) {
+                pairs.push((srcs[k % srcs.len()].clone(), dsts[k % dsts.len()].clone()));
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// Turn a port name into a glob that also matches it once its client got
+/// another PID: every run of 3 digits or more in the client name becomes `*`
+pub fn generalize(port_name: &str) -> String {
+    fn push_digits(out: &mut String, digits: &mut String) {
+        out.push_str(if digits.len() >= 3 { "*" } else { digits });
+        digits.clear();
+    }
+
+    let (client, port) = match port_name.find(':') {
+        Some(i) => port_name.split_at(i),
+        None => (port_name, ""),
+    };
+
+    let mut out = String::new();
+    let mut digits = String::new();
+    for c in client.chars() {
+        if c.is_digit(10) {
+            digits.push(c);
+        } else {
+            push_digits(&mut out, &mut digits);
+            out.push(c);
+        }
+    }
+    push_digits(&mut out, &mut digits);
+    out + port
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn list(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(o, i)| (o.to_owned(), i.to_owned())).collect()
+    }
+
+    #[test]
+    fn exact_names_match_only_themselves() {
+        let p = Pattern::parse("system:capture_1").unwrap();
+        assert!(p.is_match("system:capture_1"));
+        assert!(!p.is_match("system:capture_10"));
+        assert!(!Pattern::is_pattern("system:capture_1"));
+    }
+
+    #[test]
+    fn globs_are_anchored() {
+        let p = Pattern::parse("system:capture_?").unwrap();
+        assert!(p.is_match("system:capture_1"));
+        assert!(!p.is_match("system:capture_10"));
+        assert!(!p.is_match("xsystem:capture_1"));
+
+        let p = Pattern::parse("*:playback_1").unwrap();
+        assert!(p.is_match("system:playback_1"));
+        assert!(!p.is_match("system:playback_10"));
+    }
+
+    #[test]
+    fn globs_escape_regex_metacharacters() {
+        let p = Pattern::parse("a.b (c)+[d]:*").unwrap();
+        assert!(p.is_match("a.b (c)+[d]:out"));
+        assert!(!p.is_match("axb (c)+[d]:out"));
+        assert!(!p.is_match("a.b cc[d]:out"));
+    }
+
+    #[test]
+    fn regexes_are_anchored() {
+        let p = Pattern::parse("/sys.*:capture_1/").unwrap();
+        assert!(p.is_match("system:capture_1"));
+        assert!(!p.is_match("system:capture_10"));
+        assert!(!p.is_match("xsystem:capture_1"));
+
+        // The anchors hold around alternations too
+        let p = Pattern::parse("/a|b/").unwrap();
+        assert!(p.is_match("a"));
+        assert!(!p.is_match("ab"));
+    }
+
+    #[test]
+    fn bad_regexes_are_errors() {
+        assert!(Pattern::parse("/(/").is_err());
+    }
+
+    #[test]
+    fn expand_substitutes_captures() {
+        let p = Pattern::parse("/(?P<client>[^:]*):capture_(\\d+)/").unwrap();
+        let caps = p.captures("system:capture_2").unwrap();
+        assert_eq!(expand("rec:in_$2", &caps), "rec:in_2");
+        assert_eq!(expand("rec:in_${2}0", &caps), "rec:in_20");
+        assert_eq!(expand("${client}:playback_$2", &caps), "system:playback_2");
+        assert_eq!(expand("$0", &caps), "system:capture_2");
+        assert_eq!(expand("a$$b", &caps), "a$b");
+        // Groups that don't exist expand to nothing
+        assert_eq!(expand("x$9", &caps), "x");
+    }
+
+    #[test]
+    fn expand_numbers_glob_wildcards() {
+        let p = Pattern::parse("*:capture_?").unwrap();
+        let caps = p.captures("system:capture_1").unwrap();
+        assert_eq!(expand("$1:playback_$2", &caps), "system:playback_1");
+    }
+
+    #[test]
+    fn expand_escapes_captures_in_regexes() {
+        let p = Pattern::parse("*:out").unwrap();
+        let caps = p.captures("a.b:out").unwrap();
+        assert_eq!(expand("/$1:in_\\d+/", &caps), "/a\\.b:in_\\d+/");
+        assert_eq!(expand("$1:in", &caps), "a.b:in");
+
+        // A `$` that isn't a reference is kept
+        assert_eq!(expand("/x$/", &caps), "/x$/");
+    }
+
+    #[test]
+    fn wants_follows_captures() {
+        let mut rules = HashMap::new();
+        rules.insert("/(.*):capture_(\\d+)/".to_owned(), set(&["recorder:in_$2"]));
+        let compiled = Rules::new(&rules);
+        assert!(compiled.wants("system:capture_3", "recorder:in_3"));
+        assert!(!compiled.wants("system:capture_3", "recorder:in_4"));
+        assert!(!compiled.wants("system:playback_3", "recorder:in_3"));
+
+        rules.insert("/(/".to_owned(), set(&["x:in"]));
+        assert!(!Rules::new(&rules).wants("(", "x:in"));
+    }
+
+    #[test]
+    fn wants_matches_compiled_inputs() {
+        let mut rules = HashMap::new();
+        rules.insert("system:capture_1".to_owned(), set(&["recorder:in_*", "/mon:(l|r)/", "Jacon:MIC L"]));
+        rules.insert("x:out".to_owned(), set(&["/y:in$/", "/(/"]));
+        let compiled = Rules::new(&rules);
+        assert!(compiled.wants("system:capture_1", "recorder:in_7"));
+        assert!(compiled.wants("system:capture_1", "mon:r"));
+        assert!(compiled.wants("system:capture_1", "Jacon:MIC L"));
+        assert!(!compiled.wants("system:capture_1", "Jacon:MIC R"));
+        assert!(!compiled.wants("system:capture_2", "Jacon:MIC L"));
+        assert!(compiled.wants("x:out", "y:in"));
+        assert!(!compiled.wants("x:out", "("));
+    }
+
+    #[test]
+    fn rule_pairs_keeps_exact_names_and_expands_patterns() {
+        let outputs = list(&["system:capture_1", "system:capture_2", "mpv:out_0"]);
+        let inputs = list(&["rec:in_1", "rec:in_2", "system:playback_1"]);
+
+        let got = rule_pairs("missing:out", &set(&["missing:in"]), &outputs, &inputs).unwrap();
+        assert_eq!(got, pairs(&[("missing:out", "missing:in")]));
+
+        let mut got = rule_pairs("system:capture_?", &set(&["rec:in_$1"]), &outputs, &inputs).unwrap();
+        got.sort();
+        assert_eq!(
+            got,
+            pairs(&[("system:capture_1", "rec:in_1"), ("system:capture_2", "rec:in_2")])
+        );
+
+        let mut got = rule_pairs("mpv:out_0", &set(&["/rec:.*/"]), &outputs, &inputs).unwrap();
+        got.sort();
+        assert_eq!(got, pairs(&[("mpv:out_0", "rec:in_1"), ("mpv:out_0", "rec:in_2")]));
+    }
+
+    #[test]
+    fn client_pairs_wraps_the_shorter_side() {
+        let outputs = list(&["mpv:out_0", "vlc:out_0", "vlc:out_1"]);
+        let inputs = list(&["Jacon:Media L", "Jacon:Media R", "Jacon:Media Out L", "Jacon:Mono M"]);
+
+        let got = client_pairs("mpv", &set(&["Jacon:Media"]), &outputs, &inputs).unwrap();
+        assert_eq!(got, pairs(&[("mpv:out_0", "Jacon:Media L"), ("mpv:out_0", "Jacon:Media R")]));
+
+        let got = client_pairs("vlc", &set(&["Jacon:Mono"]), &outputs, &inputs).unwrap();
+        assert_eq!(got, pairs(&[("vlc:out_0", "Jacon:Mono M"), ("vlc:out_1", "Jacon:Mono M")]));
+
+        // A destination without a channel is every input of a client
+        let got = client_pairs("mpv", &set(&["Jacon"]), &outputs, &inputs).unwrap();
+        assert_eq!(got.len(), inputs.len());
+    }
+
+    #[test]
+    fn client_pairs_expands_captures() {
+        let outputs = list(&["alsa-jack.jackP.123.0:out_000", "alsa-jack.jackP.123.0:out_001"]);
+        let inputs = list(&["Jacon:123 L", "Jacon:123 R"]);
+        let got = client_pairs("alsa-jack.jackP.*.0", &set(&["Jacon:$1"]), &outputs, &inputs).unwrap();
+        assert_eq!(
+            got,
+            pairs(&[
+                ("alsa-jack.jackP.123.0:out_000", "Jacon:123 L"),
+                ("alsa-jack.jackP.123.0:out_001", "Jacon:123 R"),
+            ])
+        );
+    }
+
+    #[test]
+    fn generalize_replaces_pids_in_client_names() {
+        assert_eq!(generalize("alsa-jack.jackP.12345.0:out_000"), "alsa-jack.jackP.*.0:out_000");
+        assert_eq!(generalize("qjackctl-12:out_1"), "qjackctl-12:out_1");
+        assert_eq!(generalize("system:capture_123"), "system:capture_123");
+        assert_eq!(generalize("1234"), "*");
+    }
+
+    #[test]
+    fn generalize_round_trips() {
+        for name in &["alsa-jack.jackP.12345.0:out_000", "Firefox 4242:left", "system:capture_1"] {
+            let glob = generalize(name);
+            let p = Pattern::parse(&glob).unwrap();
+            assert!(p.is_match(name), "{} should match {}", glob, name);
+        }
+
+        let p = Pattern::parse(&generalize("alsa-jack.jackP.12345.0:out_000")).unwrap();
+        assert!(p.is_match("alsa-jack.jackP.678.0:out_000"));
+        assert!(!p.is_match("alsa-jack.jackP.678.0:out_001"));
+    }
+
+    #[test]
+    fn ranked_prefers_the_longest_literal_prefix() {
+        let keys = list(&["*", "/system:.*/", "system:*", "system:capture_*", "system:capture_1"]);
+        let ranked = Ranked::new(&keys);
+        assert_eq!(ranked.find(&["system:capture_1"]), Some("system:capture_*"));
+        assert_eq!(ranked.find(&["system:playback_1"]), Some("system:*"));
+        assert_eq!(ranked.find(&["other:out"]), Some("*"));
+        assert_eq!(ranked.find(&["nothing", "system:x"]), Some("system:*"));
+    }
+
+    #[test]
+    fn ranked_breaks_ties_by_name() {
+        let keys = list(&["b*", "a*", "/b.*/", "/a.*/"]);
+        let ranked = Ranked::new(&keys);
+        assert_eq!(ranked.find(&["ab"]), Some("a*"));
+        assert_eq!(ranked.find(&["ba"]), Some("b*"));
+
+        let keys = list(&["/.*b/", "/a.*/"]);
+        assert_eq!(Ranked::new(&keys).find(&["ab"]), Some("/.*b/"));
+    }
+
+    #[test]
+    fn exact_names_are_not_patterns() {
+        for name in &["system", "a*b", "what?", "/x/", "/", "(pid 12)", "$1"] {
+            let p = Pattern::parse(&exact(name)).unwrap();
+            assert!(p.is_match(name), "{} should match itself", name);
+        }
+        assert_eq!(exact("system"), "system");
+        assert!(!Pattern::parse(&exact("a*b")).unwrap().is_match("axxb"));
+        assert!(!Pattern::parse(&exact("what?")).unwrap().is_match("whats"));
+        assert!(!Pattern::parse(&exact("/x/")).unwrap().is_match("x"));
+    }
+
+    #[test]
+    fn escaped_refs_expand_to_the_name() {
+        let caps = Pattern::parse("/(?P<x>.*)/").unwrap().captures("x").unwrap();
+        for name in &["Jacon:$1", "Jacon:$$", "Jacon:${x}", "Jacon:plain"] {
+            assert_eq!(expand(&escape_refs(name), &caps), *name);
+        }
+    }
+}
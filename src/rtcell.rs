@@ -0,0 +1,68 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Lock-free hand-over of a value to the realtime thread.
+///
+/// Any thread can `publish` a new value; a single realtime thread reads the
+/// latest one through `with`, which never blocks, allocates or frees.
+/// Replaced values are kept around by the publishing side until the reader
+/// has been seen leaving `with` at least once since the swap, and are only
+/// ever dropped from `publish`.
+pub struct RtCell<T> {
+    current: AtomicPtr<T>,
+    // Number of times the reader left `with`
+    reads: AtomicUsize,
+    retired: Mutex<Vec<(usize, Box<T>)>>,
+    // Shares `T` between threads, so require what `Arc<T>` would
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> RtCell<T> {
+    pub fn new(val: T) -> Self {
+        RtCell {
+            current: AtomicPtr::new(Box::into_raw(Box::new(val))),
+            reads: AtomicUsize::new(0),
+            retired: Mutex::new(Vec::new()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Run `f` on the latest published value. Must only be called from a
+    /// single (realtime) thread.
+    pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        let ret = f(unsafe { &*self.current.load(Ordering::SeqCst) });
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        ret
+    }
+
+    /// Replace the value seen by the reader and free the values it can no
+    /// longer be looking at.
+    pub fn publish(&self, val: T) {
+        let new = Box::into_raw(Box::new(val));
+        let old = self.current.swap(new, Ordering::SeqCst);
+        let reads = self.reads.load(Ordering::SeqCst);
+
+        self.retired
+            .lock()
+            .unwrap()
+            .push((reads, unsafe { Box::from_raw(old) }));
+        self.collect();
+    }
+
+    /// Free the replaced values the reader can no longer be looking at
+    pub fn collect(&self) {
+        let reads = self.reads.load(Ordering::SeqCst);
+        self.retired.lock().unwrap().retain(|&(r, _)| r == reads);
+    }
+}
+
+impl<T> Drop for RtCell<T> {
+    fn drop(&mut self) {
+        let cur = self.current.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !cur.is_null() {
+            drop(unsafe { Box::from_raw(cur) });
+        }
+    }
+}
@@ -2,14 +2,58 @@ extern crate serde;
 extern crate serde_json;
 extern crate slog;
 
-use std::io::{Read, Write};
+use std;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+use config;
+use config::Access;
 
 use std::error::Error;
 use utils::LogError;
 
+/// TCP port the server listens on when none is given
+pub const DEFAULT_PORT: u16 = 56065;
+
+/// How long a write to a client that stopped reading may block
+const WRITE_TIMEOUT_MS: u64 = 5000;
+
+/// Id of the next client connection
+static NEXT_CONN: AtomicUsize = AtomicUsize::new(1);
+
+/// Return codes of the replies (`Response::ret`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// Unknown command
+    BadCommand = 1,
+    /// No such port, channel or subscription
+    NotFound = 2,
+    /// Missing or insufficient token
+    Unauthorized = 3,
+    /// Unknown target
+    BadTarget = 4,
+    /// Missing or invalid options
+    BadArgs = 5,
+    /// The message isn't a valid command
+    ParseError = 6,
+    /// The command is valid but could not be carried out
+    Failed = 7,
+    /// Something went wrong in the server itself
+    Internal = 8,
+}
+
 #[derive(Serialize)]
 pub struct Response<'a> {
     pub ret: i32,
@@ -17,36 +61,158 @@ pub struct Response<'a> {
     pub obj: serde_json::Value,
 }
 
-pub fn write_response(log: &slog::Logger, r: &Response, stream: &mut TcpStream) {
-    let msg = serde_json::to_string(&r).unwrap();
-    // let msg = format!("Bad command: `{}`", command.cmd);
-    let _ = stream.write(msg.as_bytes());
-    let _ = stream.write(b"\n");
-    let _ = stream.flush().log_err(&log);
+impl<'a> Response<'a> {
+    pub fn error(code: ErrorCode, msg: &'a str) -> Self {
+        Response {
+            ret: code as i32,
+            msg,
+            obj: serde_json::Value::Null,
+        }
+    }
+}
+
+/// A connection to a client, over TCP or a Unix socket
+#[derive(Debug)]
+enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match *self {
+            Conn::Tcp(ref s) => Conn::Tcp(s.try_clone()?),
+            Conn::Unix(ref s) => Conn::Unix(s.try_clone()?),
+        })
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match *self {
+            Conn::Tcp(ref s) => s.set_write_timeout(timeout),
+            Conn::Unix(ref s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    fn peer(&self) -> String {
+        match *self {
+            Conn::Tcp(ref s) => s
+                .peer_addr()
+                .map(|a| format!("{}", a))
+                .unwrap_or("unknown".to_owned()),
+            Conn::Unix(_) => "unix socket".to_owned(),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut s) => s.read(buf),
+            Conn::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut s) => s.write(buf),
+            Conn::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Conn::Tcp(ref mut s) => s.flush(),
+            Conn::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// A client connection as handed to the command handlers, along with the
+/// `id` of the command being answered so that replies can carry it
+#[derive(Debug)]
+pub struct Stream {
+    stream: Conn,
+    id: Option<serde_json::Value>,
+    conn: usize,
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Stream {
+            stream: self.stream.try_clone()?,
+            id: self.id.clone(),
+            conn: self.conn,
+        })
+    }
+
+    /// Id of the connection, the same for every command sent over it
+    pub fn conn(&self) -> usize {
+        self.conn
+    }
+}
+
+pub fn write_response(log: &slog::Logger, r: &Response, stream: &mut Stream) {
+    let _ = try_write_response(log, r, stream).log_err(&log);
+}
+
+/// Like `write_response` but hands write errors back to the caller, e.g. to
+/// find out that a streaming client went away
+pub fn try_write_response(
+    log: &slog::Logger,
+    r: &Response,
+    stream: &mut Stream,
+) -> std::io::Result<()> {
+    let mut reply = serde_json::to_value(&r).unwrap();
+    if let (Some(id), Some(obj)) = (stream.id.as_ref(), reply.as_object_mut()) {
+        obj.insert("id".to_owned(), id.clone());
+    }
+    let msg = reply.to_string();
+
+    // One write per reply so that replies from different threads don't get
+    // interleaved
+    let mut buf = msg.clone().into_bytes();
+    buf.push(b'\n');
+    stream.stream.write_all(&buf)?;
+    stream.stream.flush()?;
     debug!(log, "{}", msg);
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Command {
+    /// Echoed back in the replies to the command
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    /// Grants access to this command only, instead of authenticating the
+    /// whole connection with `auth`
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
     pub target: String,
     pub cmd: String,
+    #[serde(default)]
     pub opts: Vec<String>,
 }
 
 pub struct CmdSender {
-    myx: Sender<(TcpStream, Command)>,
-    con: Sender<(TcpStream, Command)>,
-    all: Sender<(TcpStream, Command)>,
+    myx: Sender<(Stream, Command)>,
+    con: Sender<(Stream, Command)>,
+    all: Sender<(Stream, Command)>,
 }
 impl CmdSender {
     pub fn new(
-        myx: Sender<(TcpStream, Command)>,
-        con: Sender<(TcpStream, Command)>,
-        all: Sender<(TcpStream, Command)>,
+        myx: Sender<(Stream, Command)>,
+        con: Sender<(Stream, Command)>,
+        all: Sender<(Stream, Command)>,
     ) -> Self {
         CmdSender { myx, con, all }
     }
-    pub fn send(&self, s: TcpStream, c: Command) {
+    /// Hand a command over to its target, or give the stream back if there
+    /// is no such target
+    pub fn send(&self, s: Stream, c: Command) -> Result<(), Stream> {
         match c.target.as_str() {
             "myx" => {
                 self.myx.send((s, c)).unwrap();
@@ -54,11 +220,12 @@ impl CmdSender {
             "con" => {
                 self.con.send((s, c)).unwrap();
             }
-            "all" => {
+            "all" | "cfg" => {
                 self.all.send((s, c)).unwrap();
             }
-            _ => {}
+            _ => return Err(s),
         }
+        Ok(())
     }
 }
 impl Clone for CmdSender {
@@ -71,27 +238,128 @@ impl Clone for CmdSender {
     }
 }
 
-fn handle_client(log: slog::Logger, mut stream: TcpStream, sender: CmdSender) {
-    let peer_addr = stream.peer_addr().unwrap();
+/// Whether a command only reads the state of the server
+fn is_read_only(cmd: &Command) -> bool {
+    match cmd.cmd.as_str() {
+        "get" | "mon" | "subscribe" | "sub" | "unsubscribe" | "unsub" | "meters" | "mtr" | "ping"
+        | "version" | "ver" => true,
+        _ => false,
+    }
+}
+
+/// Access granted by a token, comparing it with every known token in full
+/// so that the time taken doesn't tell how close a guess was
+fn token_access(tokens: &HashMap<String, Access>, token: &str) -> Option<Access> {
+    let mut access = None;
+    for (t, a) in tokens {
+        let same = t.len() == token.len()
+            && t.bytes().zip(token.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+        if same {
+            access = Some(*a);
+        }
+    }
+    access
+}
+
+/// Serve a client connection: every line it sends is a command, and the
+/// connection stays open until the client closes it
+fn handle_client(
+    log: slog::Logger,
+    stream: Conn,
+    sender: CmdSender,
+    tokens: Arc<HashMap<String, Access>>,
+) {
+    let peer_addr = stream.peer();
     info!(log, "New connection from: {}", peer_addr);
+    let conn = NEXT_CONN.fetch_add(1, Ordering::SeqCst);
 
-    loop {
-        let mut ibuff = [0; 256];
-        // let mut obuff = [0; 256];
-        let _ = stream.read(&mut ibuff).log_err(&log);
+    // Without tokens, there is nothing to authenticate with
+    let mut access = if tokens.is_empty() {
+        Some(Access::Full)
+    } else {
+        None
+    };
 
-        let mut ibuff = ibuff.to_vec();
-        ibuff.retain(|&b| b != 0);
-        let ibuff = String::from_utf8_lossy(ibuff.as_slice());
+    let reader = match stream.try_clone().log_err(&log) {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return,
+    };
 
-        debug!(log, "RECVD: {}", ibuff);
-        let cmd: Result<Command, serde_json::error::Error> = 
-            serde_json::from_str(&ibuff);
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(log, "Reading from client failed: {}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
 
+        debug!(log, "RECVD: {}", line);
+        let cmd: Result<Command, serde_json::error::Error> = serde_json::from_str(&line);
+
+        let mut client = match stream.try_clone().log_err(&log) {
+            Ok(s) => Stream { stream: s, id: None, conn },
+            Err(_) => break,
+        };
         match cmd {
             Ok(cmd) => {
-                sender.send(stream.try_clone().unwrap(), cmd);
-                return;
+                client.id = cmd.id.clone();
+
+                if cmd.cmd == "auth" {
+                    let granted = cmd.opts.get(0).and_then(|t| token_access(&tokens, t));
+                    let res = match granted {
+                        Some(a) => {
+                            info!(log, "Client authenticated ({:?})", a);
+                            access = Some(a);
+                            Response {
+                                ret: ErrorCode::Ok as i32,
+                                msg: "authenticated",
+                                obj: json!({ "access": a }),
+                            }
+                        }
+                        None if tokens.is_empty() => Response {
+                            ret: ErrorCode::Ok as i32,
+                            msg: "authenticated",
+                            obj: json!({ "access": Access::Full }),
+                        },
+                        None => {
+                            warn!(log, "Client failed to authenticate");
+                            Response::error(ErrorCode::Unauthorized, "Bad token!")
+                        }
+                    };
+                    write_response(&log, &res, &mut client);
+                    continue;
+                }
+
+                let granted = match cmd.token {
+                    Some(ref t) if !tokens.is_empty() => token_access(&tokens, t),
+                    _ => access,
+                };
+                let allowed = match granted {
+                    Some(Access::Full) => true,
+                    Some(Access::ReadOnly) => is_read_only(&cmd),
+                    None => false,
+                };
+                if !allowed {
+                    warn!(log, "Refused unauthorized command `{}`", cmd.cmd);
+                    write_response(
+                        &log,
+                        &Response::error(ErrorCode::Unauthorized, "Not authorized!"),
+                        &mut client,
+                    );
+                    continue;
+                }
+
+                if let Err(mut client) = sender.send(client, cmd) {
+                    write_response(
+                        &log,
+                        &Response::error(ErrorCode::BadTarget, "Bad target!"),
+                        &mut client,
+                    );
+                }
             }
             Err(e) => {
                 error!(
@@ -102,32 +370,143 @@ fn handle_client(log: slog::Logger, mut stream: TcpStream, sender: CmdSender) {
                     e.line(),
                     e.column()
                 );
-                let _ = stream.write(e.description().as_bytes());
-                return;
+                // Still try to answer with the `id` if there is one
+                client.id = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|v| v.get("id").cloned());
+                write_response(
+                    &log,
+                    &Response::error(ErrorCode::ParseError, e.description()),
+                    &mut client,
+                );
             }
         }
     }
+
+    info!(log, "Connection closed: {}", peer_addr);
 }
 
-pub fn start(log: slog::Logger, sender: CmdSender) {
-    let listener = TcpListener::bind("127.0.0.1:56065").unwrap();
+/// Serve a newly accepted client in its own thread
+fn accept(
+    log: &slog::Logger,
+    sender: &CmdSender,
+    tokens: &Arc<HashMap<String, Access>>,
+    stream: std::io::Result<Conn>,
+) {
+    let log = log.clone();
+    let sender = sender.clone();
+    let tokens = tokens.clone();
+    let _ = stream.log_err(&log).map(move |s| {
+        // Clones share the socket, so this covers every reply and event
+        let _ = s
+            .set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS)))
+            .log_err(&log);
+        thread::spawn(move || {
+            handle_client(log.new(o!("peer address" => s.peer())), s, sender, tokens);
+        });
+    });
+}
+
+/// Address to listen on from `ADDR:PORT`, `ADDR` (on the default port) or
+/// `PORT` (on localhost)
+pub fn parse_listen(addr: &str) -> Option<SocketAddr> {
+    let addr = addr.trim();
+    addr.parse::<SocketAddr>()
+        .ok()
+        .or_else(|| addr.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, DEFAULT_PORT)))
+        .or_else(|| {
+            addr.parse::<u16>()
+                .ok()
+                .map(|port| SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port))
+        })
+}
+
+/// Default path of the Unix socket: `$XDG_RUNTIME_DIR/jamyx.sock`
+pub fn default_socket_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| Path::new(&dir).join("jamyx.sock"))
+}
 
-    // accept and handle incoming connections
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            let log = log.clone();
-            let sender = sender.clone();
-            let _ = stream
-                .log_err(&log)
-                .map(move |s| {
-                    thread::spawn(move || {
-                        handle_client(
-                            log.new(o!("peer address" => format!("{}", s.peer_addr().unwrap()))),
-                            s,
-                            sender,
-                        );
-                    });
-                });
+/// Bind the Unix socket at `path`, readable and writable by the owner only
+fn bind_unix(log: &slog::Logger, path: &Path) -> std::io::Result<UnixListener> {
+    // Take over the socket left behind by a previous instance, but not the
+    // one of an instance that is still running, nor anything that isn't a
+    // socket
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another server is listening on this socket",
+            ));
+        }
+        warn!(log, "Removing stale socket at {:?}", path);
+        fs::remove_file(path)?;
+    }
+
+    // Bound in a directory only we can enter, and only moved in place once
+    // nobody else can use it
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("socket");
+    let res = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&dir).log_err(log);
+    res
+}
+
+/// Start listening for clients. Returns the path of the Unix socket, if
+/// one was bound, for it to be removed on exit.
+pub fn start(log: slog::Logger, sender: CmdSender, cfg: &config::ServerConfig) -> Option<PathBuf> {
+    let tokens = Arc::new(cfg.tokens.clone());
+    if !tokens.is_empty() {
+        info!(log, "Clients need a token ({} known)", tokens.len());
+    }
+
+    for addr in &cfg.listen {
+        let listener = match parse_listen(addr) {
+            Some(a) => TcpListener::bind(a).log_err(&log),
+            None => {
+                error!(log, "Bad listen address: `{}`", addr);
+                continue;
+            }
+        };
+        if let Ok(listener) = listener {
+            info!(log, "Listening on {}", addr);
+            let (log, sender, tokens) = (log.clone(), sender.clone(), tokens.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    accept(&log, &sender, &tokens, stream.map(Conn::Tcp));
+                }
+            });
+        }
+    }
+
+    let path = cfg.socket_path();
+    let path = path.and_then(|path| match bind_unix(&log, &path) {
+        Ok(listener) => {
+            info!(log, "Listening on {:?}", path);
+            let (log, sender, tokens) = (log.clone(), sender.clone(), tokens.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    accept(&log, &sender, &tokens, stream.map(Conn::Unix));
+                }
+            });
+            Some(path)
+        }
+        Err(e) => {
+            error!(log, "Could not listen on {:?}: {}", path, e);
+            None
         }
     });
+    path
 }